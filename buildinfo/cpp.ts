@@ -1 +1,3 @@
-// TODO: Provide a way to figure out the dependencies of a .cpp file.
+// To find the headers that a .cpp file depends on, compile it with
+// `-MD -MF <file>.d` and set `depfile` to the absolute path of the .d file.
+// The headers it lists are tracked automatically from the next build.
@@ -2,12 +2,26 @@ export type BuildEnvironment =  {
   [key: string]: string;
 };
 
+// Unset limits are left as they are. See `ResourceLimits` in buildinfo.rs.
+export interface ResourceLimits {
+  cpuSeconds?: number;
+  memoryBytes?: number;
+  processes?: number;
+  openFiles?: number;
+}
+
 export interface BuildCommand {
   command: string[];
   inputs: string[];
   outputs: string[];
   workingDir: string;
   env: BuildEnvironment;
+  depfile?: string;
+  dyndep?: string;
+  network?: boolean;
+  limits?: ResourceLimits;
+  // In seconds.
+  timeout?: number;
 }
 
 export interface TestCommand {
@@ -15,6 +29,19 @@ export interface TestCommand {
   inputs: string[];
   workingDir: string;
   env: BuildEnvironment;
+  network?: boolean;
+  limits?: ResourceLimits;
+  // In seconds.
+  timeout?: number;
+  // Always rerun the test instead of reusing its last passing result.
+  noCache?: boolean;
+}
+
+// The contents of a command's `dyndep` file.
+export interface DynamicDependencies {
+  inputs?: { [output: string]: string[] };
+  outputs?: { [output: string]: string[] };
+  commands?: BuildCommand[];
 }
 
 export type TestSet = {
@@ -25,6 +52,11 @@ export interface BuildDescription {
   commands: BuildCommand[];
   tests: TestSet;
   sandboxedDirs: string[];
+  env?: BuildEnvironment;
+  passEnv?: string[];
+  limits?: ResourceLimits;
+  // In seconds.
+  timeout?: number;
 }
 
 export function exportBuild(desc: BuildDescription) {
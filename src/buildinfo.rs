@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 
 /// A build command. All paths are absolute.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BuildCommand {
     /// Command to run.
@@ -13,13 +13,33 @@ pub struct BuildCommand {
     pub outputs: Vec<String>,
     /// The working dir.
     pub working_dir: String,
-    /// The environment variables. Currently this is in addition to the ambient
-    /// environment but at some point it would make sense to clean it.
+    /// The environment variables. These are added to `BuildInfo::env`.
     pub env: HashMap<String, String>,
+    /// Makefile-style dependency file that the command writes, e.g. with
+    /// `gcc -MD -MF`. The files it lists are recorded as extra inputs for the
+    /// next build, so headers don't need to be declared up front.
+    #[serde(default)]
+    pub depfile: Option<String>,
+    /// A file (which must also be one of `outputs`) that the command writes
+    /// `DynamicDependencies` JSON to. The graph is updated from it before any
+    /// commands that depend on this one are started.
+    #[serde(default)]
+    pub dyndep: Option<String>,
+    /// Whether the command may use the network. Otherwise it only has a
+    /// loopback interface.
+    #[serde(default)]
+    pub network: bool,
+    /// Limits on the resources it may use, on top of `BuildInfo::limits`.
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// How long it may run for, in seconds, before it is killed. Defaults to
+    /// `BuildInfo::timeout`.
+    #[serde(default)]
+    pub timeout: Option<u64>,
 }
 
 /// A test. All paths are absolute.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TestCommand {
     /// Command to run.
@@ -28,9 +48,77 @@ pub struct TestCommand {
     pub inputs: Vec<String>,
     /// The working dir.
     pub working_dir: String,
-    /// The environment variables. Currently this is in addition to the ambient
-    /// environment but at some point it would make sense to clean it.
+    /// The environment variables. These are added to `BuildInfo::env`.
     pub env: HashMap<String, String>,
+    /// Whether the test may use the network. Otherwise it only has a
+    /// loopback interface.
+    #[serde(default)]
+    pub network: bool,
+    /// Limits on the resources it may use, on top of `BuildInfo::limits`.
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// How long it may run for, in seconds, before it is killed. Defaults to
+    /// `BuildInfo::timeout`.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Always run the test, even if it passed last time and nothing it
+    /// depends on has changed, e.g. because it is flaky or uses the network.
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
+/// Limits on the resources that a command may use. The ones that aren't set
+/// are left as they are for build_exact itself.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceLimits {
+    /// CPU time in seconds, for each process (`RLIMIT_CPU`).
+    #[serde(default)]
+    pub cpu_seconds: Option<u64>,
+    /// Memory in bytes: for the whole command if it runs in a cgroup
+    /// (`--cgroup`), otherwise the address space of each process
+    /// (`RLIMIT_AS`).
+    #[serde(default)]
+    pub memory_bytes: Option<u64>,
+    /// Number of processes: in the command's cgroup if there is one,
+    /// otherwise all of the user's processes (`RLIMIT_NPROC`).
+    #[serde(default)]
+    pub processes: Option<u64>,
+    /// Open files, for each process (`RLIMIT_NOFILE`).
+    #[serde(default)]
+    pub open_files: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// These limits, with the ones that aren't set taken from `defaults`.
+    pub fn or(&self, defaults: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_seconds: self.cpu_seconds.or(defaults.cpu_seconds),
+            memory_bytes: self.memory_bytes.or(defaults.memory_bytes),
+            processes: self.processes.or(defaults.processes),
+            open_files: self.open_files.or(defaults.open_files),
+        }
+    }
+}
+
+/// Dependencies that are only discovered during the build, read from a
+/// command's `dyndep` file. All paths are absolute.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DynamicDependencies {
+    /// Extra inputs for existing commands, keyed by one of the command's
+    /// outputs. The commands must not have started yet, so they should depend
+    /// on the dyndep file.
+    #[serde(default)]
+    pub inputs: HashMap<String, Vec<String>>,
+    /// Extra outputs for existing commands (usually the one that wrote the
+    /// dyndep file), keyed by one of the command's outputs.
+    #[serde(default)]
+    pub outputs: HashMap<String, Vec<String>>,
+    /// New commands to add to the graph. They are always run (if they are
+    /// out of date).
+    #[serde(default)]
+    pub commands: Vec<BuildCommand>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,4 +133,21 @@ pub struct BuildInfo {
     /// can be read and written without explicitly declaring it in
     /// BuildCommands.inputs/outputs.
     pub sandboxed_dirs: Vec<String>,
+    /// Environment variables for every command, e.g. a pinned `PATH`.
+    /// Commands start with an empty environment plus a few defaults (or the
+    /// environment build_exact is run in, with `--inherit-env`), then these,
+    /// then `pass_env`, then their own `env`.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Names of environment variables to copy from the environment that
+    /// build_exact is run in. Changing them reruns everything.
+    #[serde(default)]
+    pub pass_env: Vec<String>,
+    /// Default resource limits for every command.
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// Default timeout for every command, in seconds. Without one commands
+    /// can run forever.
+    #[serde(default)]
+    pub timeout: Option<u64>,
 }
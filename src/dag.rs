@@ -1,18 +1,32 @@
-use crate::buildinfo::{BuildCommand, BuildInfo, TestCommand};
+use crate::buildinfo::{BuildCommand, BuildInfo, DynamicDependencies, ResourceLimits, TestCommand};
 use crate::dag_walker::walk_recursively;
+use crate::depfile::read_depfile;
+use crate::environment::{command_env, set_command_env, EnvOptions, Scratch, JUNIT_OUTPUT_FILE};
+use crate::events::{BuildEvent, EventLog};
+use crate::fingerprint::{hash_command, hash_files, hash_test_command, Fingerprints};
 use crate::graphviz::show_graphviz;
-use anyhow::{anyhow, bail, Result};
-use petgraph::algo::is_cyclic_directed;
+use crate::learn_deps::{self, LearnDepsReport, LearnedDependencies};
+use crate::logs::{keep_junit, log_output, print_log, read_log, LogIndex};
+use crate::resources::{apply_limits, run_to_completion, CgroupParent, Completed, ResourceUsage};
+use crate::sandbox::{describe_violation, sandboxed_command, SandboxAccess, SandboxKind, SandboxedCommand};
+use crate::state::{build_command_key, test_command_key, BuildState, CommandRecord};
+use crate::summary::{BuildSummary, TestResult, TestStatus};
+use crate::tracer::{TraceReport, Violation};
+use anyhow::{anyhow, bail, Context, Result};
+use petgraph::algo::{is_cyclic_directed, toposort};
 use petgraph::dot::{Config, Dot};
 use petgraph::visit::IntoNodeReferences;
 use petgraph::{Direction, Graph, graph::NodeIndex};
 use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::path::Path;
-use std::process::{Command, ExitStatus, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use std::str::FromStr;
-use std::time::SystemTime;
-use log::{info, debug, error};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use log::{info, debug, error, warn};
 
 // Hmm the graph nodes are commands, and the *edges* are files.
 
@@ -32,13 +46,166 @@ use log::{info, debug, error};
 
 type InputFileIndex = usize;
 
+/// Priority of a command that is ready to run; higher runs first. This is the
+/// estimated time in milliseconds from starting the command to finishing the
+/// requested targets.
+type CommandPriority = u64;
+
+/// Duration to assume for commands that have never been run, in milliseconds.
+const UNKNOWN_DURATION_MS: u64 = 1000;
+
 enum CommandIndex {
     BuildCommandIndex(usize),
     TestCommandIndex(usize),
 }
 
-pub struct BuildDag<'a> {
-    info: &'a BuildInfo,
+/// Options controlling how `BuildDag::build` runs commands.
+#[derive(Clone)]
+pub struct BuildOptions {
+    /// How to sandbox commands.
+    pub sandbox: SandboxKind,
+    /// Where sandboxes that need per-command directories create them.
+    pub execroot_dir: PathBuf,
+    /// Decide whether commands need to be rerun by comparing content hashes
+    /// with the ones recorded in the build state, instead of using mtimes.
+    pub content_hash: bool,
+    /// Show the build graph before building.
+    pub visualise: bool,
+    /// Maximum number of commands to run concurrently.
+    pub jobs: usize,
+    /// How commands' environments are set up.
+    pub env: EnvOptions,
+    /// Let all commands use the network, not just the ones that ask for it.
+    pub allow_network: bool,
+    /// Rerun every command and write a report of the files each one actually
+    /// used to this file. `sandbox` should be `SandboxKind::Learn`.
+    pub learn_deps: Option<PathBuf>,
+    /// Run each command in its own cgroup under this one.
+    pub cgroup: Option<CgroupParent>,
+    /// Stop starting new commands once this many have failed (not counting
+    /// tests that ran and failed), or never if it is 0. Commands that depend
+    /// on failed ones are always skipped.
+    pub keep_going: usize,
+    /// Where each command's output is logged.
+    pub log_dir: PathBuf,
+    /// Print the output of every command that runs, not just failed ones.
+    pub verbose: bool,
+    /// Reuse the results of tests that passed last time, if nothing they
+    /// depend on has changed.
+    pub cache_test_results: bool,
+}
+
+/// The work for a single node, cloned out of the `BuildInfo` so that it can
+/// be sent to a worker thread, with its `BuildState` key or test name. Its
+/// `env` is replaced by the full environment from `command_env()`, and its
+/// `limits` and `timeout` include the build-wide defaults. Build commands
+/// also get the fingerprints from the last time they were run, if any, and
+/// whether their environment has changed since then, and their inputs
+/// include the ones that were discovered from their depfile. Tests get the
+/// fingerprints from the last time they passed, if that was their last run.
+enum Job {
+    Build(String, BuildCommand, Option<Fingerprints>, bool),
+    Test(String, TestCommand, Option<Fingerprints>),
+}
+
+/// What happened when a `Job` was run successfully. `Built` is `None` if the
+/// command was already up to date, and `Tested` is `None` if the test's
+/// last result was reused.
+enum JobOutcome {
+    Built(Option<CommandRun>),
+    Tested(String, Option<CommandRun>),
+}
+
+/// Information about a command that was actually run.
+struct CommandRun {
+    duration: Duration,
+    exit_status: ExitStatus,
+    usage: ResourceUsage,
+    /// Whether it was killed because it ran for too long.
+    timed_out: bool,
+    /// Only calculated in content hash mode for build commands, and for
+    /// tests that passed and whose results can be cached.
+    fingerprints: Option<Fingerprints>,
+    /// Inputs read from the command's depfile, if it has one.
+    discovered_inputs: Vec<String>,
+    /// How the files it used differ from its declarations, in learn mode.
+    learned: Option<LearnedDependencies>,
+    /// Accesses that the sandbox blocked, if it can tell.
+    violations: Vec<Violation>,
+}
+
+/// The error for a build command that exits unsuccessfully or times out. It
+/// keeps the sandbox violations (which are probably why it failed) so that
+/// they can be reported as events as well as in the message.
+#[derive(Debug)]
+struct CommandFailed {
+    command: Vec<String>,
+    exit_status: ExitStatus,
+    /// The timeout, if it was killed for running longer than that.
+    timed_out_after: Option<Duration>,
+    violations: Vec<Violation>,
+    /// Whether to mention that it couldn't use the network, because its
+    /// output looks like it tried and the sandbox can't tell us.
+    network_hint: bool,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.timed_out_after {
+            Some(timeout) => write!(f, "Build command {:?} timed out after {:?}", self.command, timeout)?,
+            None => write!(f, "Build command {:?} failed with {}", self.command, self.exit_status)?,
+        }
+        for violation in self.violations.iter() {
+            write!(f, "\n  {}", describe_violation(violation, false))?;
+        }
+        if self.network_hint {
+            write!(f, "\n  {}", NETWORK_HINT)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Added to failures of commands that couldn't use the network and whose
+/// output looks like they tried to.
+const NETWORK_HINT: &str = "It had no network access; set `network: true` if it needs it \
+    (`--sandbox seccomp` reports exactly what was blocked).";
+
+/// Lowercase parts of the error messages that commands usually print when
+/// they can't reach the network or resolve a host name.
+const NETWORK_ERRORS: &[&str] = &[
+    "network is unreachable",
+    "temporary failure in name resolution",
+    "name or service not known",
+    "no address associated with hostname",
+    "could not resolve host",
+    "failed to lookup address",
+    "getaddrinfo",
+];
+
+/// Whether a failed command with `key` should get `NETWORK_HINT`: it had no
+/// network access, the sandbox can't tell us if it tried to use it, and its
+/// output mentions a network error.
+fn needs_network_hint(network: bool, key: &str, options: &BuildOptions) -> bool {
+    if network || options.allow_network || options.sandbox.reports_violations() {
+        return false;
+    }
+    let output = read_log(&options.log_dir, key).ok().flatten().unwrap_or_default();
+    let output = String::from_utf8_lossy(&output).to_lowercase();
+    NETWORK_ERRORS.iter().any(|error| output.contains(error))
+}
+
+/// Changes made to the graph by a dyndep file.
+struct DyndepChanges {
+    /// Nodes for the commands that were added.
+    new_commands: Vec<NodeIndex>,
+}
+
+pub struct BuildDag {
+    /// The build description. This is owned because dyndep files can add
+    /// commands and files to it during the build.
+    info: BuildInfo,
     /// The DAG. Also, we have the index of the input file for the
     /// command that this goes into for debugging purposes.
     dag: Graph::<CommandIndex, InputFileIndex>,
@@ -54,6 +221,10 @@ pub struct BuildDag<'a> {
     build_command_node_index: Vec<NodeIndex>,
     /// Node index for each build command.
     test_command_node_index: Vec<NodeIndex>,
+    /// The `BuildState` key for each build command. These are worked out
+    /// when the command is added, because dyndep files can add outputs to it
+    /// later, and it must have the same key in the next build.
+    build_command_keys: Vec<String>,
 
 }
 
@@ -90,25 +261,27 @@ impl FromStr for Target {
     }
 }
 
-impl<'a> BuildDag<'a> {
-    pub fn new(info: &'a BuildInfo) -> Result<Self> {
-        ensure_absolute_normalised_paths(info)?;
+impl BuildDag {
+    pub fn new(info: BuildInfo) -> Result<Self> {
+        ensure_absolute_normalised_paths(&info)?;
+        ensure_dyndep_files_are_outputs(&info.commands)?;
 
         let mut bd = Self {
-            info,
             dag: Graph::new(),
             output_file_generators: HashMap::new(),
             input_file_consumers: HashMap::new(),
             test_names: Vec::with_capacity(info.tests.len()),
             build_command_node_index: Vec::with_capacity(info.commands.len()),
             test_command_node_index: Vec::with_capacity(info.tests.len()),
+            build_command_keys: info.commands.iter().map(build_command_key).collect(),
+            info,
         };
 
         // Go through all the commands and add all the inputs and output files.
         // Output files can only be from one command so return an error if we try
         // to the same output file twice.
 
-        for (build_command_index, command) in info.commands.iter().enumerate() {
+        for (build_command_index, command) in bd.info.commands.iter().enumerate() {
             let node_index = bd.dag.add_node(CommandIndex::BuildCommandIndex(build_command_index));
             bd.build_command_node_index.push(node_index);
 
@@ -126,7 +299,7 @@ impl<'a> BuildDag<'a> {
         }
 
         // Now add the build command edges.
-        for (build_command_index, command) in info.commands.iter().enumerate() {
+        for (build_command_index, command) in bd.info.commands.iter().enumerate() {
             let node_index = bd.build_command_node_index[build_command_index];
 
             for (input_index, input) in command.inputs.iter().enumerate() {
@@ -143,7 +316,7 @@ impl<'a> BuildDag<'a> {
 
         // Collect the test names so we can iterate over them in a consistent order
         // and look them up by index.
-        bd.test_names = info.tests.keys().map(ToOwned::to_owned).collect();
+        bd.test_names = bd.info.tests.keys().map(ToOwned::to_owned).collect();
 
         // Add tests directly after build commands.
         for test_command_index in 0..bd.test_names.len() {
@@ -155,7 +328,7 @@ impl<'a> BuildDag<'a> {
         for (test_command_index, test_name) in bd.test_names.iter().enumerate() {
             let node_index = bd.test_command_node_index[test_command_index];
 
-            let command = &info.tests[test_name];
+            let command = &bd.info.tests[test_name];
 
             for (input_index, input) in command.inputs.iter().enumerate() {
                 // Add an edge pointing to the command that generates this file (if any;
@@ -175,6 +348,19 @@ impl<'a> BuildDag<'a> {
         Ok(bd)
     }
 
+    /// The keys in the `BuildState` (and for the logs) of the commands that
+    /// generate each output, including ones added by dyndep files.
+    pub fn log_index(&self) -> LogIndex {
+        LogIndex {
+            outputs: self
+                .output_file_generators
+                .iter()
+                .map(|(output, node_index)| (output.clone(), self.node_key(*node_index)))
+                .collect(),
+            tests: self.info.tests.keys().cloned().collect(),
+        }
+    }
+
     /// Add the target commands to the set of commands that needs to be built.
     fn add_target_commands(&self, target: &Target, to: &mut HashSet<NodeIndex>) -> Result<()> {
         match target {
@@ -278,8 +464,10 @@ impl<'a> BuildDag<'a> {
         Ok(())
     }
 
-    /// Build files and run tests, depending on the value of targets.
-    pub fn build(&self, targets: &[Target], no_sandbox: bool, visualise: bool) -> Result<()> {
+    /// Build files and run tests, depending on the value of targets. Commands
+    /// failing doesn't make this return an error; they are logged and
+    /// included in the summary.
+    pub fn build(&mut self, targets: &[Target], options: &BuildOptions, state: &mut BuildState, events: &mut EventLog) -> Result<BuildSummary> {
 
         let mut commands_to_run: HashSet<NodeIndex> = HashSet::with_capacity(self.dag.node_count());
         for target in targets {
@@ -291,45 +479,209 @@ impl<'a> BuildDag<'a> {
         let mut command_dependencies_remaining =
             HashMap::<NodeIndex, usize>::with_capacity(commands_to_run.len());
 
-        // Commands that are ready to run. TODO: Sort in priority order: BinaryHeap::<(CommandPriority, NodeIndex)>, with CommandPriority = i32.
-        let mut ready_to_run = BinaryHeap::<NodeIndex>::new();
+        let mut priorities = self.critical_path_priorities(&commands_to_run, state);
+
+        // Commands that are ready to run, in priority order.
+        let mut ready_to_run = BinaryHeap::<(CommandPriority, NodeIndex)>::new();
 
         for command_index in &commands_to_run {
             let dependencies = self.dag.neighbors_directed(*command_index, Direction::Incoming).count();
 
             if dependencies == 0 {
-                ready_to_run.push(*command_index);
+                ready_to_run.push((priorities[command_index], *command_index));
             } else {
                 command_dependencies_remaining.insert(*command_index, dependencies);
             }
         }
 
         // Show visualisation if requested.
-        if visualise {
+        if options.visualise {
             self.show_visualisation(&commands_to_run)?;
         }
 
-        // Now we can start building!
+        // Now we can start building! Each command runs on its own thread and
+        // reports back on this channel when it is done, so all the bookkeeping
+        // stays on this thread.
+        let (result_sender, result_receiver) = mpsc::channel::<(NodeIndex, Result<JobOutcome>)>();
+        let mut running = 0;
+
+        // Commands that failed, and the ones that won't be run because they
+        // depend on them. Once `keep_going` commands have failed we stop
+        // starting new ones, but wait for the ones that are already running so
+        // we don't leave orphaned processes behind.
+        let mut failed = Vec::<NodeIndex>::new();
+        let mut skipped = HashSet::<NodeIndex>::new();
+        let mut summary = BuildSummary::default();
+
+        // Commands that have been started (including finished ones), and ones
+        // that have finished. These are needed to update the bookkeeping when
+        // a dyndep file changes the graph.
+        let mut started = HashSet::<NodeIndex>::with_capacity(commands_to_run.len());
+        let mut finished = HashSet::<NodeIndex>::with_capacity(commands_to_run.len());
+
+        let mut learned_deps = LearnDepsReport::default();
+
+        loop {
+            let stopping = options.keep_going != 0 && failed.len() >= options.keep_going;
+            while running < options.jobs && !stopping {
+                let node_index = match ready_to_run.pop() {
+                    Some((_, node_index)) => node_index,
+                    None => break,
+                };
+                started.insert(node_index);
+                events.emit(BuildEvent::CommandStarted {
+                    key: self.node_key(node_index),
+                    command: self.command_line(node_index).to_vec(),
+                });
+
+                let job = self.job_for_node(node_index, state, options);
+                let sandboxed_dirs = self.info.sandboxed_dirs.clone();
+                let options = options.clone();
+                let result_sender = result_sender.clone();
 
-        // TODO: This can easily be multithreaded.
-        while let Some(node_index) = ready_to_run.pop() {
-            let node_weight = self.dag.node_weight(node_index).expect("Internal logic error 2");
-            match node_weight {
-                CommandIndex::BuildCommandIndex(build_command_index) => {
-                    run_command_if_necessary(&self.info.commands[*build_command_index], &self.info.sandboxed_dirs, no_sandbox)?;
+                thread::spawn(move || {
+                    let result = run_job(job, &sandboxed_dirs, &options);
+                    // The receiver only goes away if the main thread panicked.
+                    let _ = result_sender.send((node_index, result));
+                });
+                running += 1;
+            }
+
+            if running == 0 {
+                break;
+            }
+
+            let (node_index, result) = result_receiver.recv().expect("Internal logic error 6");
+            running -= 1;
+            finished.insert(node_index);
+
+            // For build commands, whether they actually ran.
+            let mut built = None;
+
+            match result {
+                Ok(JobOutcome::Built(run)) => {
+                    self.report_finished(node_index, run.as_ref(), events);
+                    built = Some(run.is_some());
+                    if let Some(mut run) = run {
+                        if options.verbose {
+                            self.print_output(node_index, options);
+                        }
+                        if let Some(learned) = run.learned.take() {
+                            learned_deps.add(self.node_key(node_index), learned);
+                        }
+                        self.record_run(node_index, run, state, options);
+                    }
+                }
+                Ok(JobOutcome::Tested(test_name, None)) => {
+                    self.report_finished(node_index, None, events);
+                    summary.tests.push(TestResult {
+                        name: test_name,
+                        status: TestStatus::Passed,
+                        duration: None,
+                        exit_code: Some(0),
+                        cached: true,
+                    });
                 }
-                CommandIndex::TestCommandIndex(test_command_index) => {
-                    let test_name = &self.test_names[*test_command_index];
-                    let test_result = run_test(&self.info.tests[test_name], &self.info.sandboxed_dirs, no_sandbox)?;
-                    if !test_result.success() {
-                        error!("Test failed! Exit status: {:?}", test_result.code());
+                Ok(JobOutcome::Tested(test_name, Some(mut run))) => {
+                    self.report_finished(node_index, Some(&run), events);
+                    if let Some(learned) = run.learned.take() {
+                        learned_deps.add(self.node_key(node_index), learned);
+                    }
+                    let test_result = run.exit_status;
+                    if options.verbose || run.timed_out || !test_result.success() {
+                        self.print_output(node_index, options);
+                    }
+                    let status = if run.timed_out {
+                        error!("Test timed out! {}", test_name);
+                        TestStatus::TimedOut
+                    } else if !test_result.success() {
+                        error!("Test failed! {} exit status: {:?}", test_name, test_result.code());
+                        let key = test_command_key(&test_name);
+                        if needs_network_hint(self.info.tests[&test_name].network, &key, options) {
+                            error!("{}", NETWORK_HINT);
+                        }
+                        TestStatus::Failed
+                    } else {
+                        TestStatus::Passed
+                    };
+                    summary.tests.push(TestResult {
+                        name: test_name,
+                        status,
+                        duration: Some(run.duration),
+                        exit_code: test_result.code(),
+                        cached: false,
+                    });
+                    self.record_run(node_index, run, state, options);
+                }
+                Err(e) => {
+                    self.report_failed(node_index, &e, events);
+                    // Other errors happen before or after running it.
+                    if e.is::<CommandFailed>() {
+                        self.print_output(node_index, options);
+                    }
+                    error!("{:?}", e);
+                    let timed_out_after = e.downcast_ref::<CommandFailed>().and_then(|failed| failed.timed_out_after);
+                    self.fail(node_index, timed_out_after, &commands_to_run, &mut failed, &mut skipped, &mut summary);
+                    continue;
+                }
+            }
+
+            // If the command has a dyndep file it may have changed the graph,
+            // so add any new commands that are needed, and recount
+            // everything that hasn't started yet. This is done whether or not
+            // the command actually ran because the graph starts from scratch
+            // in each build.
+            let dyndep_changes = match self.dyndep_file(node_index) {
+                Some(dyndep) => match self.apply_dyndep(node_index, &dyndep, &started) {
+                    Ok(changes) => Some(changes),
+                    Err(e) => {
+                        error!("{:?}", e);
+                        self.fail(node_index, None, &commands_to_run, &mut failed, &mut skipped, &mut summary);
+                        continue;
                     }
                 },
+                None => None,
+            };
+
+            match built {
+                Some(true) => summary.succeeded.push(self.node_key(node_index)),
+                Some(false) => summary.up_to_date.push(self.node_key(node_index)),
+                None => {}
+            }
+
+            if let Some(changes) = dyndep_changes {
+                self.add_dyndep_commands(targets, &changes, &started, &mut commands_to_run)?;
+
+                // New commands may depend on ones that already failed.
+                for failed_index in failed.iter() {
+                    self.skip_dependants(*failed_index, &commands_to_run, &mut skipped);
+                }
+
+                priorities = self.critical_path_priorities(&commands_to_run, state);
+                command_dependencies_remaining.clear();
+                ready_to_run.clear();
+
+                for command_index in &commands_to_run {
+                    if started.contains(command_index) || skipped.contains(command_index) {
+                        continue;
+                    }
+                    let dependencies = self.dag
+                        .neighbors_directed(*command_index, Direction::Incoming)
+                        .filter(|parent_index| !finished.contains(parent_index))
+                        .count();
+
+                    if dependencies == 0 {
+                        ready_to_run.push((priorities[command_index], *command_index));
+                    } else {
+                        command_dependencies_remaining.insert(*command_index, dependencies);
+                    }
+                }
+                continue;
             }
 
             // Now decrement the required number of dependencies for its dependants.
             for child_index in self.dag.neighbors_directed(node_index, Direction::Outgoing) {
-                if commands_to_run.contains(&child_index) {
+                if commands_to_run.contains(&child_index) && !skipped.contains(&child_index) {
                     let remaining = command_dependencies_remaining
                         .get_mut(&child_index)
                         .expect("Internal logic error 5");
@@ -337,17 +689,518 @@ impl<'a> BuildDag<'a> {
                     *remaining -= 1;
                     if *remaining == 0 {
                         command_dependencies_remaining.remove(&child_index);
-                        ready_to_run.push(child_index);
+                        ready_to_run.push((priorities[&child_index], child_index));
                     }
                 }
             }
         }
 
-        assert!(command_dependencies_remaining.is_empty());
+        // Write the report even if the build failed, since it may explain why.
+        if let Some(path) = &options.learn_deps {
+            learned_deps.write(path)?;
+            info!("Wrote dependency report to {:?}", path);
+        }
+
+        if failed.is_empty() {
+            assert!(command_dependencies_remaining.is_empty());
+        }
+
+        // That includes the ones that never became ready because we stopped.
+        for node_index in commands_to_run.iter().filter(|node_index| !started.contains(node_index)) {
+            match self.dag[*node_index] {
+                CommandIndex::BuildCommandIndex(_) => summary.skipped.push(self.node_key(*node_index)),
+                CommandIndex::TestCommandIndex(test_command_index) => summary.tests.push(TestResult {
+                    name: self.test_names[test_command_index].clone(),
+                    status: TestStatus::Skipped,
+                    duration: None,
+                    exit_code: None,
+                    cached: false,
+                }),
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Record that a command failed, or was killed for running longer than
+    /// `timed_out_after`, and skip everything that depends on it.
+    fn fail(
+        &self,
+        node_index: NodeIndex,
+        timed_out_after: Option<Duration>,
+        commands_to_run: &HashSet<NodeIndex>,
+        failed: &mut Vec<NodeIndex>,
+        skipped: &mut HashSet<NodeIndex>,
+        summary: &mut BuildSummary,
+    ) {
+        failed.push(node_index);
+        match self.dag[node_index] {
+            CommandIndex::BuildCommandIndex(_) => match timed_out_after {
+                Some(timeout) => summary.timed_out.push((self.node_key(node_index), timeout)),
+                None => summary.failed.push(self.node_key(node_index)),
+            },
+            // It couldn't be run at all.
+            CommandIndex::TestCommandIndex(test_command_index) => summary.tests.push(TestResult {
+                name: self.test_names[test_command_index].clone(),
+                status: TestStatus::Failed,
+                duration: None,
+                exit_code: None,
+                cached: false,
+            }),
+        }
+        self.skip_dependants(node_index, commands_to_run, skipped);
+    }
+
+    /// Add the commands in `commands_to_run` that depend (directly or not) on
+    /// `node_index` to `skipped`.
+    fn skip_dependants(&self, node_index: NodeIndex, commands_to_run: &HashSet<NodeIndex>, skipped: &mut HashSet<NodeIndex>) {
+        for child_index in self.dag.neighbors_directed(node_index, Direction::Outgoing) {
+            walk_recursively(&self.dag, child_index, Direction::Outgoing, |dependant_index| {
+                commands_to_run.contains(&dependant_index) && skipped.insert(dependant_index)
+            });
+        }
+    }
+
+    /// Emit events for a command that finished, and warn about any sandbox
+    /// violations (they don't always make the command fail). `run` is `None`
+    /// if it was up to date.
+    fn report_finished(&self, node_index: NodeIndex, run: Option<&CommandRun>, events: &mut EventLog) {
+        let key = self.node_key(node_index);
+        let is_test = matches!(self.dag[node_index], CommandIndex::TestCommandIndex(_));
+
+        if let Some(run) = run {
+            for violation in run.violations.iter() {
+                warn!("{}: {}", key, describe_violation(violation, is_test));
+                events.emit(BuildEvent::sandbox_violation(
+                    key.clone(),
+                    self.command_line(node_index).to_vec(),
+                    violation,
+                    is_test,
+                ));
+            }
+        }
+
+        events.emit(BuildEvent::CommandFinished {
+            key,
+            ran: run.is_some(),
+            success: run.map(|run| run.exit_status.success() && !run.timed_out).unwrap_or(true),
+            timed_out: run.map(|run| run.timed_out).unwrap_or(false),
+            exit_code: run.and_then(|run| run.exit_status.code()),
+            duration_ms: run.map(|run| run.duration.as_millis() as u64),
+            peak_rss_bytes: run.map(|run| run.usage.peak_rss_bytes),
+            cpu_time_ms: run.map(|run| run.usage.cpu_time.as_millis() as u64),
+            error: None,
+        });
+    }
+
+    /// Emit events for a command that couldn't be run or failed. Violations
+    /// are already in the error message.
+    fn report_failed(&self, node_index: NodeIndex, error: &anyhow::Error, events: &mut EventLog) {
+        let key = self.node_key(node_index);
+        let failed = error.downcast_ref::<CommandFailed>();
+
+        if let Some(failed) = failed {
+            for violation in failed.violations.iter() {
+                events.emit(BuildEvent::sandbox_violation(key.clone(), failed.command.clone(), violation, false));
+            }
+        }
+
+        events.emit(BuildEvent::CommandFinished {
+            key,
+            ran: failed.is_some(),
+            success: false,
+            timed_out: failed.map(|failed| failed.timed_out_after.is_some()).unwrap_or(false),
+            exit_code: failed.and_then(|failed| failed.exit_status.code()),
+            duration_ms: None,
+            peak_rss_bytes: None,
+            cpu_time_ms: None,
+            error: Some(format!("{:#}", error)),
+        });
+    }
+
+    /// The dyndep file of a node, if it is a build command that has one.
+    fn dyndep_file(&self, node_index: NodeIndex) -> Option<String> {
+        match self.dag.node_weight(node_index).expect("Internal logic error 10") {
+            CommandIndex::BuildCommandIndex(build_command_index) => {
+                self.info.commands[*build_command_index].dyndep.clone()
+            }
+            CommandIndex::TestCommandIndex(_) => None,
+        }
+    }
+
+    /// Read the dyndep file written by `node_index` and add the extra inputs,
+    /// outputs and commands that it lists to the graph. Only commands that
+    /// haven't been `started` can be changed (apart from adding outputs to
+    /// `node_index` itself).
+    fn apply_dyndep(&mut self, node_index: NodeIndex, path: &str, started: &HashSet<NodeIndex>) -> Result<DyndepChanges> {
+        let contents = fs::read(path).with_context(|| format!("Reading dyndep file {:?}", path))?;
+        let dyndep: DynamicDependencies = serde_json::from_slice(&contents)
+            .with_context(|| format!("Parsing dyndep file {:?}", path))?;
+
+        for command in dyndep.commands.iter() {
+            check_command_paths(command)?;
+        }
+        ensure_dyndep_files_are_outputs(&dyndep.commands)?;
+
+        // Check everything before changing the graph, so that a bad dyndep
+        // file doesn't leave it half extended.
+        let mut new_inputs = Vec::new();
+        for (output, inputs) in dyndep.inputs.iter() {
+            let target_index = self.build_command_generating(output)?;
+            if started.contains(&target_index) {
+                bail!(
+                    "Dyndep file {:?} adds inputs to the command that generates {:?}, but it has already started",
+                    path,
+                    output
+                );
+            }
+            for input in inputs {
+                check_path(Path::new(input))?;
+                new_inputs.push((target_index, input.as_str()));
+            }
+        }
+
+        // Map from each new output to the node that will generate it.
+        let mut new_outputs = HashMap::<&str, NodeIndex>::new();
+        let mut added_outputs = Vec::new();
+        for (output, outputs) in dyndep.outputs.iter() {
+            let target_index = self.build_command_generating(output)?;
+            if target_index != node_index && started.contains(&target_index) {
+                bail!(
+                    "Dyndep file {:?} adds outputs to the command that generates {:?}, but it has already started",
+                    path,
+                    output
+                );
+            }
+            for new_output in outputs {
+                check_path(Path::new(new_output))?;
+                self.ensure_not_consumed_yet(new_output, started)?;
+                let generator = self.output_file_generators.get(new_output).or_else(|| new_outputs.get(new_output.as_str()));
+                match generator {
+                    Some(generator) if *generator == target_index => continue,
+                    Some(_) => bail!("File '{}' is specified as the output of more than one command.", new_output),
+                    None => {}
+                }
+                new_outputs.insert(new_output, target_index);
+                added_outputs.push((target_index, new_output.as_str()));
+            }
+        }
+
+        // New commands get the next node indices.
+        let first_new_index = self.dag.node_count();
+        for (i, command) in dyndep.commands.iter().enumerate() {
+            for output in command.outputs.iter() {
+                self.ensure_not_consumed_yet(output, started)?;
+                if self.output_file_generators.contains_key(output) || new_outputs.contains_key(output.as_str()) {
+                    bail!("File '{}' is specified as the output of more than one command.", output);
+                }
+                new_outputs.insert(output, NodeIndex::new(first_new_index + i));
+            }
+            for input in command.inputs.iter() {
+                new_inputs.push((NodeIndex::new(first_new_index + i), input.as_str()));
+            }
+        }
+
+        // Add the new edges to a copy of the graph to check for cycles.
+        let mut graph = self.dag.map(|_, _| (), |_, _| ());
+        for _ in dyndep.commands.iter() {
+            graph.add_node(());
+        }
+        for (consumer_index, input) in new_inputs.iter() {
+            if let Some(generator) = self.output_file_generators.get(*input).or_else(|| new_outputs.get(input)) {
+                graph.add_edge(*generator, *consumer_index, ());
+            }
+        }
+        for (output, generator) in new_outputs.iter() {
+            let existing_consumers = self.input_file_consumers.get(*output).into_iter().flatten();
+            let new_consumers = new_inputs.iter().filter(|(_, input)| input == output).map(|(consumer_index, _)| consumer_index);
+            for consumer_index in existing_consumers.chain(new_consumers) {
+                graph.add_edge(*generator, *consumer_index, ());
+            }
+        }
+        ensure_not_cyclic(&graph).with_context(|| format!("After applying dyndep file {:?}", path))?;
+
+        for (target_index, input) in new_inputs.iter().filter(|(target_index, _)| target_index.index() < first_new_index) {
+            self.add_input(*target_index, input);
+        }
+        for (target_index, output) in added_outputs {
+            self.add_output(target_index, output)?;
+        }
+
+        let mut new_commands = Vec::with_capacity(dyndep.commands.len());
+        for command in dyndep.commands.iter() {
+            let build_command_index = self.info.commands.len();
+            let new_node_index = self.dag.add_node(CommandIndex::BuildCommandIndex(build_command_index));
+            self.build_command_node_index.push(new_node_index);
+            self.build_command_keys.push(build_command_key(command));
+            self.info.commands.push(command.clone());
+
+            for input in command.inputs.iter() {
+                self.connect_input(new_node_index, input);
+            }
+            for output in command.outputs.iter() {
+                self.connect_output(new_node_index, output)?;
+            }
+            new_commands.push(new_node_index);
+        }
+
+        Ok(DyndepChanges { new_commands })
+    }
+
+    /// Add the commands that are needed after a dyndep file has changed the
+    /// graph to `commands_to_run`. Commands that haven't `started` may have
+    /// new inputs that are generated by commands that weren't needed before,
+    /// and the `targets` may now include new commands (e.g. `all`). New
+    /// commands that nothing needs aren't run.
+    fn add_dyndep_commands(
+        &self,
+        targets: &[Target],
+        changes: &DyndepChanges,
+        started: &HashSet<NodeIndex>,
+        commands_to_run: &mut HashSet<NodeIndex>,
+    ) -> Result<()> {
+        for target in targets {
+            self.add_target_commands(target, commands_to_run)?;
+        }
+        let pending_commands = commands_to_run
+            .iter()
+            .filter(|node_index| !started.contains(node_index))
+            .copied()
+            .collect::<Vec<_>>();
+        for start_node_index in pending_commands {
+            walk_recursively(&self.dag, start_node_index, Direction::Incoming, |node_index| {
+                let inserted = commands_to_run.insert(node_index);
+                node_index == start_node_index || inserted
+            });
+        }
+        for node_index in changes.new_commands.iter().filter(|node_index| !commands_to_run.contains(node_index)) {
+            debug!("Not running command added by a dyndep file that nothing needs: {:?}", self.command_line(*node_index));
+        }
+        Ok(())
+    }
+
+    /// Find the node of the build command that generates `output`.
+    fn build_command_generating(&self, output: &str) -> Result<NodeIndex> {
+        self.output_file_generators
+            .get(output)
+            .copied()
+            .ok_or_else(|| anyhow!("No command generates output {:?}", output))
+    }
+
+    /// Return an error if a command that has started uses `file` as an input,
+    /// since it is too late to generate it.
+    fn ensure_not_consumed_yet(&self, file: &str, started: &HashSet<NodeIndex>) -> Result<()> {
+        if let Some(consumers) = self.input_file_consumers.get(file) {
+            if consumers.iter().any(|consumer| started.contains(consumer)) {
+                bail!("Dynamic output {:?} is used by a command that has already started", file);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an input to an existing build command.
+    fn add_input(&mut self, node_index: NodeIndex, input: &str) {
+        let build_command_index = match self.dag.node_weight(node_index).expect("Internal logic error 11") {
+            CommandIndex::BuildCommandIndex(build_command_index) => *build_command_index,
+            CommandIndex::TestCommandIndex(_) => panic!("Internal logic error 12"),
+        };
+        let command = &mut self.info.commands[build_command_index];
+        if command.inputs.iter().any(|existing| existing == input) {
+            return;
+        }
+        command.inputs.push(input.to_owned());
+        self.connect_input(node_index, input);
+    }
 
+    /// Add an output to an existing build command.
+    fn add_output(&mut self, node_index: NodeIndex, output: &str) -> Result<()> {
+        let build_command_index = match self.dag.node_weight(node_index).expect("Internal logic error 13") {
+            CommandIndex::BuildCommandIndex(build_command_index) => *build_command_index,
+            CommandIndex::TestCommandIndex(_) => panic!("Internal logic error 14"),
+        };
+        let command = &mut self.info.commands[build_command_index];
+        if command.outputs.iter().any(|existing| existing == output) {
+            return Ok(());
+        }
+        command.outputs.push(output.to_owned());
+        self.connect_output(node_index, output)
+    }
+
+    /// Add an edge from the command that generates `input` (if any) to
+    /// `node_index`, and record that `node_index` consumes it. `input` must
+    /// already be in the command's inputs.
+    fn connect_input(&mut self, node_index: NodeIndex, input: &str) {
+        if let Some(parent_index) = self.output_file_generators.get(input) {
+            let input_index = self.input_index(node_index, input);
+            self.dag.add_edge(*parent_index, node_index, input_index);
+        }
+        self.input_file_consumers.entry(input.to_owned()).or_default().push(node_index);
+    }
+
+    /// Record that `node_index` generates `output`, and add edges to all the
+    /// commands that consume it.
+    fn connect_output(&mut self, node_index: NodeIndex, output: &str) -> Result<()> {
+        if self.output_file_generators.insert(output.to_owned(), node_index).is_some() {
+            bail!(
+                "File '{}' is specified as the output of more than one command.",
+                output
+            );
+        }
+        let consumers = self.input_file_consumers.get(output).cloned().unwrap_or_default();
+        for consumer_index in consumers {
+            let input_index = self.input_index(consumer_index, output);
+            self.dag.add_edge(node_index, consumer_index, input_index);
+        }
         Ok(())
     }
 
+    /// The index of `input` in a node's inputs, which is used as the edge
+    /// weight.
+    fn input_index(&self, node_index: NodeIndex, input: &str) -> InputFileIndex {
+        let inputs = match self.dag.node_weight(node_index).expect("Internal logic error 15") {
+            CommandIndex::BuildCommandIndex(build_command_index) => &self.info.commands[*build_command_index].inputs,
+            CommandIndex::TestCommandIndex(test_command_index) => &self.info.tests[&self.test_names[*test_command_index]].inputs,
+        };
+        inputs.iter().position(|existing| existing == input).expect("Internal logic error 16")
+    }
+
+    /// Work out the priority of each command we are going to run. This is the
+    /// length of the longest path from the start of the command to any of
+    /// the requested targets, using the durations recorded in previous builds.
+    /// Running the commands on that critical path first minimises the total
+    /// build time.
+    fn critical_path_priorities(&self, commands_to_run: &HashSet<NodeIndex>, state: &BuildState) -> HashMap<NodeIndex, CommandPriority> {
+        let mut priorities = HashMap::<NodeIndex, CommandPriority>::with_capacity(commands_to_run.len());
+
+        // Visit dependants before their dependencies so their priorities are
+        // already known.
+        let sorted = toposort(&self.dag, None).expect("Internal logic error 7");
+        for node_index in sorted.into_iter().rev() {
+            if !commands_to_run.contains(&node_index) {
+                continue;
+            }
+
+            let duration = state
+                .command(&self.node_key(node_index))
+                .map(|record| record.duration_ms)
+                .unwrap_or(UNKNOWN_DURATION_MS);
+
+            let longest_dependant = self.dag
+                .neighbors_directed(node_index, Direction::Outgoing)
+                .filter_map(|child_index| priorities.get(&child_index))
+                .max()
+                .copied()
+                .unwrap_or(0);
+
+            priorities.insert(node_index, duration + longest_dependant);
+        }
+        priorities
+    }
+
+    /// Save the details of a command that was run in the build state.
+    fn record_run(&self, node_index: NodeIndex, run: CommandRun, state: &mut BuildState, options: &BuildOptions) {
+        let node_weight = self.dag.node_weight(node_index).expect("Internal logic error 9");
+        let (command_line, env) = match node_weight {
+            CommandIndex::BuildCommandIndex(build_command_index) => {
+                let command = &self.info.commands[*build_command_index];
+                (&command.command, &command.env)
+            }
+            CommandIndex::TestCommandIndex(test_command_index) => {
+                let command = &self.info.tests[&self.test_names[*test_command_index]];
+                (&command.command, &command.env)
+            }
+        };
+
+        let record = CommandRecord {
+            command_line: command_line.clone(),
+            env: command_env(&options.env, &self.info.env, env),
+            duration_ms: run.duration.as_millis() as u64,
+            exit_status: run.exit_status.code(),
+            peak_rss_bytes: Some(run.usage.peak_rss_bytes),
+            cpu_time_ms: Some(run.usage.cpu_time.as_millis() as u64),
+            fingerprints: run.fingerprints,
+            discovered_inputs: run.discovered_inputs,
+            buildinfo_hash: state.buildinfo_hash.clone(),
+        };
+        state.set_command(self.node_key(node_index), record);
+    }
+
+    /// Print the output of the last run of a node's command.
+    fn print_output(&self, node_index: NodeIndex, options: &BuildOptions) {
+        if let Err(e) = print_log(&options.log_dir, &self.node_key(node_index)) {
+            warn!("{:?}", e);
+        }
+    }
+
+    /// The command line that a node runs.
+    fn command_line(&self, node_index: NodeIndex) -> &[String] {
+        match self.dag.node_weight(node_index).expect("Internal logic error 17") {
+            CommandIndex::BuildCommandIndex(build_command_index) => {
+                &self.info.commands[*build_command_index].command
+            }
+            CommandIndex::TestCommandIndex(test_command_index) => {
+                &self.info.tests[&self.test_names[*test_command_index]].command
+            }
+        }
+    }
+
+    /// The key for a node in the `BuildState`.
+    fn node_key(&self, node_index: NodeIndex) -> String {
+        let node_weight = self.dag.node_weight(node_index).expect("Internal logic error 8");
+        match node_weight {
+            CommandIndex::BuildCommandIndex(build_command_index) => {
+                self.build_command_keys[*build_command_index].clone()
+            }
+            CommandIndex::TestCommandIndex(test_command_index) => {
+                test_command_key(&self.test_names[*test_command_index])
+            }
+        }
+    }
+
+    /// Clone the command for a node so it can be run on another thread.
+    fn job_for_node(&self, node_index: NodeIndex, state: &BuildState, options: &BuildOptions) -> Job {
+        let node_weight = self.dag.node_weight(node_index).expect("Internal logic error 2");
+        match node_weight {
+            CommandIndex::BuildCommandIndex(build_command_index) => {
+                let mut command = self.info.commands[*build_command_index].clone();
+                command.env = command_env(&options.env, &self.info.env, &command.env);
+                command.limits = command.limits.or(&self.info.limits);
+                command.timeout = command.timeout.or(self.info.timeout);
+                let key = self.node_key(node_index);
+                let record = state.command(&key);
+
+                // Add the inputs that its depfile listed last time, so they
+                // are checked for changes and allowed by the sandbox. Ones
+                // that have since been deleted make it out of date, and the
+                // sandboxes skip them.
+                if let Some(record) = record {
+                    let declared_inputs: HashSet<&String> = command.inputs.iter().collect();
+                    let discovered_inputs: Vec<String> = record.discovered_inputs
+                        .iter()
+                        .filter(|input| !declared_inputs.contains(input))
+                        .cloned()
+                        .collect();
+                    command.inputs.extend(discovered_inputs);
+                }
+
+                let previous = record.and_then(|record| record.fingerprints.clone());
+                let env_changed = record.map(|record| record.env != command.env).unwrap_or(false);
+                Job::Build(key, command, previous, env_changed)
+            }
+            CommandIndex::TestCommandIndex(test_command_index) => {
+                let test_name = &self.test_names[*test_command_index];
+                let mut command = self.info.tests[test_name].clone();
+                command.env = command_env(&options.env, &self.info.env, &command.env);
+                command.limits = command.limits.or(&self.info.limits);
+                command.timeout = command.timeout.or(self.info.timeout);
+                let previous = state
+                    .command(&self.node_key(node_index))
+                    .and_then(|record| record.fingerprints.clone());
+                Job::Test(test_name.clone(), command, previous)
+            }
+        }
+    }
+
     fn show_visualisation(&self, highlight_commands: &HashSet<NodeIndex>) -> Result<()> {
         // Map the graph node/edges to strings. See
         // https://github.com/petgraph/petgraph/issues/194
@@ -376,7 +1229,7 @@ impl<'a> BuildDag<'a> {
                         &self.info.tests[test_name].inputs[*edge_weight]
                     }
                 };
-                input.split('/').last().expect("Internal logic error").clone()
+                input.rsplit('/').next().expect("Internal logic error")
             }
         );
 
@@ -419,24 +1272,8 @@ digraph {{
 /// in them. That makes everything way easier, and Typescript can easily take
 /// care of it.
 fn ensure_absolute_normalised_paths(info: &BuildInfo) -> Result<()> {
-    fn check_path(path: &Path) -> Result<()> {
-        if !path.is_absolute() {
-            bail!("Path {:?} must be absolute.", path);
-        }
-        if path.iter().any(|component| component == ".." || component == ".") {
-            bail!("Path {:?} must be canonical (no .. or .).", path);
-        }
-        Ok(())
-    }
-
     for command in info.commands.iter() {
-        for input in command.inputs.iter() {
-            check_path(Path::new(input))?;
-        }
-        for output in command.outputs.iter() {
-            check_path(Path::new(output))?;
-        }
-        check_path(Path::new(&command.working_dir))?;
+        check_command_paths(command)?;
     }
 
     for dir in info.sandboxed_dirs.iter() {
@@ -446,6 +1283,46 @@ fn ensure_absolute_normalised_paths(info: &BuildInfo) -> Result<()> {
     Ok(())
 }
 
+fn check_command_paths(command: &BuildCommand) -> Result<()> {
+    for input in command.inputs.iter() {
+        check_path(Path::new(input))?;
+    }
+    for output in command.outputs.iter() {
+        check_path(Path::new(output))?;
+    }
+    if let Some(depfile) = &command.depfile {
+        check_path(Path::new(depfile))?;
+    }
+    if let Some(dyndep) = &command.dyndep {
+        check_path(Path::new(dyndep))?;
+    }
+    check_path(Path::new(&command.working_dir))
+}
+
+fn check_path(path: &Path) -> Result<()> {
+    if !path.is_absolute() {
+        bail!("Path {:?} must be absolute.", path);
+    }
+    if path.iter().any(|component| component == ".." || component == ".") {
+        bail!("Path {:?} must be canonical (no .. or .).", path);
+    }
+    Ok(())
+}
+
+/// Dyndep files must be declared as outputs of the command that writes them
+/// so that the sandbox allows it, and so that commands that depend on them
+/// wait for them.
+fn ensure_dyndep_files_are_outputs(commands: &[BuildCommand]) -> Result<()> {
+    for command in commands {
+        if let Some(dyndep) = &command.dyndep {
+            if !command.outputs.contains(dyndep) {
+                bail!("Dyndep file {:?} must be one of the command's outputs.", dyndep);
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Return an error if the directed graph is cyclic.
 fn ensure_not_cyclic<NW, EW>(graph: &Graph<NW, EW>) -> Result<()> {
     if is_cyclic_directed(graph) {
@@ -455,6 +1332,20 @@ fn ensure_not_cyclic<NW, EW>(graph: &Graph<NW, EW>) -> Result<()> {
     Ok(())
 }
 
+/// Run a job. This is called on a worker thread.
+fn run_job(job: Job, sandboxed_dirs: &[String], options: &BuildOptions) -> Result<JobOutcome> {
+    match job {
+        Job::Build(key, command, previous, env_changed) => {
+            let run = run_command_if_necessary(&key, &command, previous.as_ref(), env_changed, sandboxed_dirs, options)?;
+            Ok(JobOutcome::Built(run))
+        }
+        Job::Test(test_name, command, previous) => {
+            let run = run_test(&test_name, &command, previous.as_ref(), sandboxed_dirs, options)?;
+            Ok(JobOutcome::Tested(test_name, run))
+        }
+    }
+}
+
 fn rerun_necessary(command: &BuildCommand) -> bool {
     // Set the max time to zero; if a command has no declared outputs then we
     // don't know when it was last run so we always need to re-run it. This
@@ -493,93 +1384,392 @@ fn rerun_necessary(command: &BuildCommand) -> bool {
             return true;
         }
     }
-    return false;
+    false
 }
 
-// Run the command but only if at least one of its inputs has a more recent
-// mtime (modified time) than its any of its outputs.
-fn run_command_if_necessary(command: &BuildCommand, sandboxed_dirs: &[String], no_sandbox: bool) -> Result<()> {
-    if !rerun_necessary(command) {
-        debug!("Skipping command (output is already up to date): {:?}", command.command);
-        return Ok(());
+/// Content hash version of `rerun_necessary()`. The command needs to be rerun
+/// if the command line or any of its input or output files are different to
+/// when it was last run. Returns the command and input part of the new
+/// fingerprints if so, since the inputs must be hashed before the command
+/// runs.
+fn rerun_necessary_by_content(command: &BuildCommand, previous: Option<&Fingerprints>) -> Result<Option<Fingerprints>> {
+    let current = Fingerprints {
+        command: hash_command(command),
+        inputs: hash_files(&command.inputs)?,
+        outputs: HashMap::new(),
+    };
+
+    let previous = match previous {
+        Some(previous) => previous,
+        // Never been run (or not in content hash mode).
+        None => return Ok(Some(current)),
+    };
+
+    if current.command != previous.command || current.inputs != previous.inputs {
+        return Ok(Some(current));
     }
-    info!("Running command: {:?}", command.command);
 
-    if command.command.is_empty() {
-        bail!("Command is empty");
+    // Check the outputs haven't been deleted or modified since.
+    let outputs = hash_files(&command.outputs)?;
+    if outputs.len() != command.outputs.len() || outputs != previous.outputs {
+        return Ok(Some(current));
     }
 
-    let mut c = if no_sandbox {
-        Command::new(&command.command[0])
+    Ok(None)
+}
+
+// Run the command but only if it is out of date. By default that means at
+// least one of its inputs has a more recent mtime (modified time) than its any
+// of its outputs, or its environment has changed, but in content hash mode
+// the file contents and environment are compared. Returns information about
+// the run, or `None` if it was up to date. Everything is rerun in learn mode,
+// since commands have to run to be traced.
+fn run_command_if_necessary(
+    key: &str,
+    command: &BuildCommand,
+    previous: Option<&Fingerprints>,
+    env_changed: bool,
+    sandboxed_dirs: &[String],
+    options: &BuildOptions,
+) -> Result<Option<CommandRun>> {
+    let learning = options.learn_deps.is_some();
+    let fingerprints = if options.content_hash {
+        let previous = if learning { None } else { previous };
+        match rerun_necessary_by_content(command, previous)? {
+            Some(fingerprints) => Some(fingerprints),
+            None => {
+                debug!("Skipping command (output hashes are up to date): {:?}", command.command);
+                return Ok(None);
+            }
+        }
     } else {
-        let mut sbc = Command::new("sandbox");
-        sbc.arg("--sandbox");
-        sbc.args(sandboxed_dirs);
-        sbc.arg("--allow-read");
-        sbc.args(&command.inputs);
-        sbc.arg("--allow-write");
-        sbc.args(&command.outputs);
-        sbc.arg("--");
-        sbc.args(&command.command);
-
-        debug!("Sandboxed command: {:?}", sbc);
-
-        sbc
+        if !learning && !env_changed && !rerun_necessary(command) {
+            debug!("Skipping command (output is already up to date): {:?}", command.command);
+            return Ok(None);
+        }
+        None
     };
+    info!("Running command: {:?}", command.command);
 
-    c.stderr(Stdio::inherit());
-    c.current_dir(&command.working_dir);
-    // TODO: Clear the environment probably.
-    // c.env_clear();
-    c.envs(&command.env);
+    let writable: Vec<String> = command.outputs.iter().chain(&command.depfile).cloned().collect();
+    let scratch = Scratch::create()?;
+    let mut sc = sandboxed_command(
+        options.sandbox,
+        &command.command,
+        Path::new(&command.working_dir),
+        &SandboxAccess {
+            sandboxed_dirs,
+            read: &command.inputs,
+            write: &writable,
+            network: command.network || options.allow_network,
+            scratch: &scratch,
+        },
+        &options.execroot_dir,
+    )?;
+
+    log_output(&mut sc.command, &options.log_dir, key)?;
+    let Execution { status, duration, usage, timed_out, report } =
+        execute(sc, &scratch, &command.env, &command.limits, command.timeout, false, options)?;
+    let violations = report.as_ref().map(|report| report.violations.clone()).unwrap_or_default();
+
+    if timed_out || !status.success() {
+        return Err(CommandFailed {
+            command: command.command.clone(),
+            exit_status: status,
+            timed_out_after: command.timeout.filter(|_| timed_out).map(Duration::from_secs),
+            network_hint: !timed_out && violations.is_empty() && needs_network_hint(command.network, key, options),
+            violations,
+        }.into());
+    }
 
-    c.args(command.command.iter().skip(1));
+    // Record the outputs as they are now so we can tell if they change.
+    let fingerprints = match fingerprints {
+        Some(fingerprints) => Some(Fingerprints {
+            outputs: hash_files(&command.outputs)?,
+            ..fingerprints
+        }),
+        None => None,
+    };
 
-    let output = c.output()?;
+    let discovered_inputs = match &command.depfile {
+        Some(depfile) => read_depfile(Path::new(depfile), Path::new(&command.working_dir))?,
+        None => Vec::new(),
+    };
 
-    if !output.status.success() {
-        bail!(
-            "Build command failed with exit status: {}",
-            output.status
-        );
-    }
+    // Inputs listed in the depfile don't need declaring.
+    let learned = report.map(|report| {
+        let inputs: Vec<String> = command.inputs.iter().chain(&discovered_inputs).cloned().collect();
+        learn_deps::compare(&command.command, &inputs, &writable, &report.accesses, sandboxed_dirs)
+    });
+
+    Ok(Some(CommandRun {
+        duration,
+        exit_status: status,
+        usage,
+        timed_out,
+        fingerprints,
+        discovered_inputs,
+        learned,
+        violations,
+    }))
+}
 
-    Ok(())
+/// Run a test, unless it passed last time with the same command line,
+/// environment, sandbox, network access and input files, in which case that
+/// result is reused and `None` is returned. Tests are always rerun in learn
+/// mode, with `--inherit-env` (since the environment they inherit isn't part
+/// of their fingerprint), and if they have `no_cache` or test results aren't
+/// being cached.
+fn run_test(
+    test_name: &str,
+    command: &TestCommand,
+    previous: Option<&Fingerprints>,
+    sandboxed_dirs: &[String],
+    options: &BuildOptions,
+) -> Result<Option<CommandRun>> {
+    let network = command.network || options.allow_network;
+    let cacheable =
+        options.cache_test_results && !command.no_cache && options.learn_deps.is_none() && options.env.hermetic;
+    let fingerprints = if cacheable {
+        let current = Fingerprints {
+            command: hash_test_command(command, options.sandbox, network),
+            inputs: hash_files(&command.inputs)?,
+            outputs: HashMap::new(),
+        };
+        if previous == Some(&current) {
+            debug!("Skipping test (passed last time with the same inputs): {:?}", command.command);
+            return Ok(None);
+        }
+        Some(current)
+    } else {
+        None
+    };
+    info!("Running test: {:?}", command.command);
+
+    let key = test_command_key(test_name);
+    let scratch = Scratch::create()?;
+    let mut sc = sandboxed_command(
+        options.sandbox,
+        &command.command,
+        Path::new(&command.working_dir),
+        &SandboxAccess {
+            sandboxed_dirs,
+            read: &command.inputs,
+            write: &[],
+            network,
+            scratch: &scratch,
+        },
+        &options.execroot_dir,
+    )?;
+
+    log_output(&mut sc.command, &options.log_dir, &key)?;
+    let Execution { status, duration, usage, timed_out, report } =
+        execute(sc, &scratch, &command.env, &command.limits, command.timeout, true, options)?;
+    keep_junit(&scratch.tmp().join(JUNIT_OUTPUT_FILE), &options.log_dir, &key)?;
+
+    let violations = report.as_ref().map(|report| report.violations.clone()).unwrap_or_default();
+    let learned = report.map(|report| {
+        learn_deps::compare(&command.command, &command.inputs, &[], &report.accesses, sandboxed_dirs)
+    });
+
+    // Only passing results are reused.
+    let passed = status.success() && !timed_out;
+    Ok(Some(CommandRun {
+        duration,
+        exit_status: status,
+        usage,
+        timed_out,
+        fingerprints: fingerprints.filter(|_| passed),
+        discovered_inputs: Vec::new(),
+        learned,
+        violations,
+    }))
+}
+
+/// What happened when a sandboxed command ran.
+struct Execution {
+    status: ExitStatus,
+    duration: Duration,
+    usage: ResourceUsage,
+    timed_out: bool,
+    report: Option<TraceReport>,
 }
 
+/// Give a sandboxed command its environment and resource limits, run it
+/// (killing it after `timeout` seconds), and clean up after it.
+fn execute(
+    mut sc: SandboxedCommand,
+    scratch: &Scratch,
+    env: &HashMap<String, String>,
+    limits: &ResourceLimits,
+    timeout: Option<u64>,
+    is_test: bool,
+    options: &BuildOptions,
+) -> Result<Execution> {
+    set_command_env(&mut sc.command, env, &options.env, scratch, &sc.tmp_dir, is_test);
+    let cgroup = options.cgroup.as_ref().map(|parent| parent.create(limits)).transpose()?;
+    apply_limits(&mut sc.command, limits, cgroup.as_ref())?;
+
+    let start = Instant::now();
+    let completed = run_to_completion(&mut sc.command, timeout.map(Duration::from_secs));
+    let duration = start.elapsed();
+    let report = sc.finish()?;
+    let Completed { status, usage, timed_out } = completed?;
+    let usage = match &cgroup {
+        Some(cgroup) => cgroup.measured(usage),
+        None => usage,
+    };
 
-fn run_test(command: &TestCommand, sandboxed_dirs: &[String], no_sandbox: bool) -> Result<ExitStatus> {
-    info!("Running test: {:?}", command.command);
+    Ok(Execution {
+        status,
+        duration,
+        usage,
+        timed_out,
+        report,
+    })
+}
 
-    if command.command.is_empty() {
-        bail!("Command is empty");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    fn command(inputs: &[&str], outputs: &[&str]) -> BuildCommand {
+        BuildCommand {
+            command: vec!["true".to_string()],
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            outputs: outputs.iter().map(|output| output.to_string()).collect(),
+            working_dir: "/src".to_string(),
+            env: HashMap::new(),
+            depfile: None,
+            dyndep: None,
+            network: false,
+            limits: ResourceLimits::default(),
+            timeout: None,
+        }
     }
 
-    let mut c = if no_sandbox {
-        Command::new(&command.command[0])
-    } else {
-        let mut sbc = Command::new("sandbox");
-        sbc.arg("--sandbox");
-        sbc.args(sandboxed_dirs);
-        sbc.arg("--allow-read");
-        sbc.args(&command.inputs);
-        sbc.arg("--");
-        sbc.args(&command.command);
-
-        debug!("Sandboxed command: {:?}", sbc);
+    /// A writes a dyndep file, B depends on A, and C reads a file that
+    /// nothing generates yet.
+    fn dyndep_dag() -> BuildDag {
+        let mut a = command(&["/src/a.in"], &["/out/a.o", "/out/a.dd"]);
+        a.dyndep = Some("/out/a.dd".to_string());
+        BuildDag::new(BuildInfo {
+            commands: vec![a, command(&["/out/a.o"], &["/out/b.o"]), command(&["/src/c.in", "/out/x.h"], &["/out/c.o"])],
+            tests: HashMap::new(),
+            sandboxed_dirs: vec!["/src".to_string(), "/out".to_string()],
+            env: HashMap::new(),
+            pass_env: Vec::new(),
+            limits: ResourceLimits::default(),
+            timeout: None,
+        })
+        .unwrap()
+    }
 
-        sbc
-    };
+    /// Everything that applying a dyndep file can change.
+    fn snapshot(bd: &BuildDag) -> String {
+        let edges: Vec<_> = bd
+            .dag
+            .raw_edges()
+            .iter()
+            .map(|edge| (edge.source().index(), edge.target().index(), edge.weight))
+            .collect();
+        let generators: BTreeMap<_, _> = bd.output_file_generators.iter().map(|(file, node)| (file, node.index())).collect();
+        let consumers: BTreeMap<_, Vec<_>> = bd
+            .input_file_consumers
+            .iter()
+            .map(|(file, nodes)| (file, nodes.iter().map(|node| node.index()).collect()))
+            .collect();
+        format!(
+            "{} {:?} {:?} {:?} {:?} {}",
+            bd.dag.node_count(),
+            edges,
+            generators,
+            consumers,
+            bd.build_command_keys,
+            serde_json::to_string(&bd.info.commands).unwrap(),
+        )
+    }
 
-    c.stderr(Stdio::inherit());
-    c.current_dir(&command.working_dir);
-    // TODO: Clear the environment probably.
-    // c.env_clear();
-    c.envs(&command.env);
+    fn write_dyndep(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
 
-    c.args(command.command.iter().skip(1));
+    #[test]
+    fn rejected_dyndep_files_leave_the_graph_unchanged() {
+        let started: HashSet<NodeIndex> = vec![NodeIndex::new(0), NodeIndex::new(2)].into_iter().collect();
+        // Each file starts with valid changes, to check they aren't applied
+        // either.
+        let valid = r#""inputs": {"/out/b.o": ["/src/extra.h"]}"#;
+        let cases = [
+            ("duplicate_output", format!(r#"{{{}, "outputs": {{"/out/a.o": ["/out/b.o"]}}}}"#, valid)),
+            (
+                "cycle",
+                format!(
+                    r#"{{"inputs": {{"/out/b.o": ["/src/extra.h", "/out/d.o"]}}, "commands": [{}]}}"#,
+                    serde_json::to_string(&command(&["/out/b.o"], &["/out/d.o"])).unwrap()
+                ),
+            ),
+            ("consumed_before_added", format!(r#"{{{}, "outputs": {{"/out/a.o": ["/out/x.h"]}}}}"#, valid)),
+            ("relative_path", format!(r#"{{{}, "outputs": {{"/out/a.o": ["x.h"]}}}}"#, valid)),
+        ];
+
+        let dir = TempDir::new().unwrap();
+        for (name, contents) in cases.iter() {
+            let mut bd = dyndep_dag();
+            let before = snapshot(&bd);
+            let path = write_dyndep(&dir, name, contents);
+            assert!(bd.apply_dyndep(NodeIndex::new(0), &path, &started).is_err(), "{} was accepted", name);
+            assert_eq!(snapshot(&bd), before, "{} changed the graph", name);
+        }
+    }
 
-    let output = c.output()?;
+    #[test]
+    fn only_needed_dyndep_commands_are_run() {
+        let mut bd = dyndep_dag();
+        let targets = vec![Target::Output("/out/c.o".to_string())];
+        let mut commands_to_run = HashSet::new();
+        bd.add_target_commands(&targets[0], &mut commands_to_run).unwrap();
+        let started: HashSet<NodeIndex> = vec![NodeIndex::new(0)].into_iter().collect();
+        commands_to_run.insert(NodeIndex::new(0));
+
+        // D generates C's missing header, from a file that E generates. F
+        // isn't needed by anything.
+        let dir = TempDir::new().unwrap();
+        let commands = vec![
+            command(&["/out/y.h"], &["/out/x.h"]),
+            command(&[], &["/out/y.h"]),
+            command(&["/out/a.o"], &["/out/f.o"]),
+        ];
+        let contents = format!(r#"{{"commands": {}}}"#, serde_json::to_string(&commands).unwrap());
+        let path = write_dyndep(&dir, "new_commands", &contents);
+        let changes = bd.apply_dyndep(NodeIndex::new(0), &path, &started).unwrap();
+        assert_eq!(changes.new_commands, vec![NodeIndex::new(3), NodeIndex::new(4), NodeIndex::new(5)]);
+        bd.add_dyndep_commands(&targets, &changes, &started, &mut commands_to_run).unwrap();
+
+        let mut scheduled: Vec<usize> = commands_to_run.iter().map(|node_index| node_index.index()).collect();
+        scheduled.sort_unstable();
+        assert_eq!(scheduled, vec![0, 2, 3, 4]);
+
+        // Unless they are in a requested target.
+        bd.add_dyndep_commands(&[Target::AllOutputs], &changes, &started, &mut commands_to_run).unwrap();
+        assert!(commands_to_run.contains(&NodeIndex::new(5)));
+    }
 
-    Ok(output.status)
+    #[test]
+    fn dyndep_outputs_dont_change_the_command_key() {
+        let mut bd = dyndep_dag();
+        let key = bd.node_key(NodeIndex::new(0));
+        let dir = TempDir::new().unwrap();
+        let path = write_dyndep(&dir, "new_output", r#"{"outputs": {"/out/a.o": ["/out/a.h"]}}"#);
+        let started: HashSet<NodeIndex> = vec![NodeIndex::new(0)].into_iter().collect();
+        bd.apply_dyndep(NodeIndex::new(0), &path, &started).unwrap();
+
+        assert_eq!(bd.info.commands[0].outputs, vec!["/out/a.o", "/out/a.dd", "/out/a.h"]);
+        assert_eq!(bd.output_file_generators["/out/a.h"], NodeIndex::new(0));
+        assert_eq!(bd.node_key(NodeIndex::new(0)), key);
+    }
 }
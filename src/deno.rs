@@ -1,7 +1,36 @@
 use crate::buildinfo::BuildInfo;
+use crate::generator::{Generator, GeneratorOutput};
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
-use std::{path::Path, process::{Command, Stdio}};
+use std::{path::{Path, PathBuf}, process::{Command, Stdio}};
+
+/// The built-in generator for Deno scripts. Instead of reporting the files it
+/// read, `deno info` tells us the checksums of all the modules the script
+/// uses.
+pub struct DenoGenerator {
+    script: PathBuf,
+}
+
+impl DenoGenerator {
+    pub fn new(script: &Path) -> Self {
+        Self {
+            script: script.to_owned(),
+        }
+    }
+}
+
+impl Generator for DenoGenerator {
+    fn hash_sources(&self, _read_files: &[String]) -> Result<Option<String>> {
+        Ok(Some(hash_buildinfo(&self.script)?))
+    }
+
+    fn run(&self) -> Result<GeneratorOutput> {
+        Ok(GeneratorOutput {
+            build_info: run_buildinfo(&self.script)?,
+            read_files: Vec::new(),
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct DenoInfo {
@@ -55,7 +84,7 @@ pub fn hash_buildinfo(file: &Path) -> Result<String> {
     for info in deno_info.modules.iter() {
         all_hashes.push_str(&info.checksum);
     }
-    return Ok(all_hashes);
+    Ok(all_hashes)
 }
 
 /// Run `deno <file>`, gather and decode BuildInfo.
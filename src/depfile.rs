@@ -0,0 +1,135 @@
+use crate::paths::normalise;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Read a Makefile-style dependency file, as written by `gcc -MD` or
+/// `clang -MD`, and return all the prerequisites it lists. Relative paths are
+/// resolved against `working_dir` and normalised so that they can be compared
+/// with the paths in the BuildInfo.
+pub fn read_depfile(path: &Path, working_dir: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path).with_context(|| format!("Reading depfile {:?}", path))?;
+
+    let mut inputs = Vec::new();
+    for prerequisite in parse_prerequisites(&contents) {
+        let absolute = normalise(&working_dir.join(prerequisite));
+        let absolute = absolute.to_string_lossy().into_owned();
+        if !inputs.contains(&absolute) {
+            inputs.push(absolute);
+        }
+    }
+    Ok(inputs)
+}
+
+/// Parse the prerequisites out of a depfile. It consists of rules like
+///
+///     foo.o: foo.cpp foo.h \
+///       bar.h
+///
+/// possibly with several targets (gcc's `-MP` adds a phony target for each
+/// header). Spaces in paths are escaped with backslashes and `$` as `$$`.
+fn parse_prerequisites(contents: &str) -> Vec<String> {
+    let mut prerequisites = Vec::new();
+
+    // Whether we are after the `:` of the current rule.
+    let mut in_prerequisites = false;
+    let mut word = String::new();
+
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                // Line continuation.
+                Some('\n') => {
+                    chars.next();
+                    finish_word(&mut word, in_prerequisites, &mut prerequisites);
+                }
+                Some('\r') => {
+                    chars.next();
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    finish_word(&mut word, in_prerequisites, &mut prerequisites);
+                }
+                Some(' ') | Some('#') | Some('\\') => {
+                    word.push(chars.next().expect("Peeked character missing"));
+                }
+                _ => word.push(c),
+            },
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                word.push('$');
+            }
+            ' ' | '\t' => finish_word(&mut word, in_prerequisites, &mut prerequisites),
+            '\r' | '\n' => {
+                finish_word(&mut word, in_prerequisites, &mut prerequisites);
+                in_prerequisites = false;
+            }
+            // A colon followed by whitespace ends the targets. Other colons
+            // can be part of a path.
+            ':' if !in_prerequisites && chars.peek().copied().unwrap_or(' ').is_whitespace() => {
+                word.clear();
+                in_prerequisites = true;
+            }
+            _ => word.push(c),
+        }
+    }
+    finish_word(&mut word, in_prerequisites, &mut prerequisites);
+
+    prerequisites
+}
+
+fn finish_word(word: &mut String, in_prerequisites: bool, prerequisites: &mut Vec<String>) {
+    if in_prerequisites && !word.is_empty() {
+        prerequisites.push(word.clone());
+    }
+    word.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_simple_rule() {
+        assert_eq!(parse_prerequisites("foo.o: foo.cpp foo.h\n"), vec!["foo.cpp", "foo.h"]);
+    }
+
+    #[test]
+    fn unescapes_spaces() {
+        assert_eq!(
+            parse_prerequisites("foo.o: my\\ dir/foo.cpp foo.h\n"),
+            vec!["my dir/foo.cpp", "foo.h"]
+        );
+    }
+
+    #[test]
+    fn joins_continuation_lines() {
+        assert_eq!(
+            parse_prerequisites("foo.o: foo.cpp \\\n  foo.h \\\r\n  bar.h\n"),
+            vec!["foo.cpp", "foo.h", "bar.h"]
+        );
+        // A continuation also ends the word before it.
+        assert_eq!(parse_prerequisites("foo.o: foo.cpp\\\nfoo.h\n"), vec!["foo.cpp", "foo.h"]);
+    }
+
+    #[test]
+    fn reads_every_rule() {
+        // As written by `gcc -MD -MP`, with a phony target for each header.
+        let contents = "foo.o bar.o: foo.cpp foo.h\n\nfoo.h:\n";
+        assert_eq!(parse_prerequisites(contents), vec!["foo.cpp", "foo.h"]);
+
+        let contents = "foo.o: foo.cpp\nbar.o: bar.cpp bar.h\n";
+        assert_eq!(parse_prerequisites(contents), vec!["foo.cpp", "bar.cpp", "bar.h"]);
+    }
+
+    #[test]
+    fn unescapes_dollars() {
+        assert_eq!(parse_prerequisites("foo.o: $$dir/foo.cpp a$b\n"), vec!["$dir/foo.cpp", "a$b"]);
+    }
+
+    #[test]
+    fn keeps_colons_in_paths() {
+        assert_eq!(parse_prerequisites("c:/foo.o: c:/foo.cpp\n"), vec!["c:/foo.cpp"]);
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Variables that every command gets in hermetic mode, unless the build
+/// description or the command overrides them.
+const DEFAULT_ENV: &[(&str, &str)] = &[
+    ("PATH", "/usr/local/bin:/usr/bin:/bin"),
+    ("LANG", "C"),
+];
+
+/// The file in a test's `TMPDIR` that it can write JUnit XML results to. Its
+/// path is passed in `XML_OUTPUT_FILE`.
+pub const JUNIT_OUTPUT_FILE: &str = "junit.xml";
+
+/// Used to give each command its own scratch directory.
+static NEXT_SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// How commands' environments are set up.
+#[derive(Clone)]
+pub struct EnvOptions {
+    /// Start from an empty environment rather than the one we were run in.
+    pub hermetic: bool,
+    /// Variables to copy from the environment we were run in, in hermetic
+    /// mode.
+    pub pass_env: Vec<String>,
+}
+
+/// Work out the environment that a command is run with, apart from the
+/// variables for its scratch directories (see `set_command_env`). In
+/// hermetic mode that is the defaults, then the build-wide `info_env`, then
+/// the variables in `pass_env` from our own environment, then the command's
+/// own `env`. Otherwise it is `info_env` then the command's `env`, which
+/// are added to our own environment.
+pub fn command_env(
+    options: &EnvOptions,
+    info_env: &HashMap<String, String>,
+    env: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut effective: HashMap<String, String> = if options.hermetic {
+        DEFAULT_ENV
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    effective.extend(info_env.iter().map(|(name, value)| (name.clone(), value.clone())));
+    if options.hermetic {
+        for name in options.pass_env.iter() {
+            if let Ok(value) = env::var(name) {
+                effective.insert(name.clone(), value);
+            }
+        }
+    }
+    effective.extend(env.iter().map(|(name, value)| (name.clone(), value.clone())));
+    effective
+}
+
+/// A directory for a single run of a command, in the system temporary
+/// directory, which is deleted when this is dropped. It is outside the
+/// sandboxed directories (as long as the temporary directory isn't in one)
+/// so commands can always write to it.
+pub struct Scratch {
+    dir: PathBuf,
+}
+
+impl Scratch {
+    pub fn create() -> Result<Self> {
+        let id = NEXT_SCRATCH.fetch_add(1, Ordering::Relaxed);
+        let dir = env::temp_dir().join(format!("build_exact-{}-{}", process::id(), id));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).with_context(|| format!("Removing stale scratch directory {:?}", dir))?;
+        }
+        let scratch = Self { dir };
+        for dir in [scratch.home(), scratch.tmp()] {
+            fs::create_dir_all(&dir).with_context(|| format!("Creating {:?}", dir))?;
+        }
+        Ok(scratch)
+    }
+
+    /// An empty directory to use as `HOME`.
+    pub fn home(&self) -> PathBuf {
+        self.dir.join("home")
+    }
+
+    /// An empty directory to use as `TMPDIR`.
+    pub fn tmp(&self) -> PathBuf {
+        self.dir.join("tmp")
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Give `command` the environment `env` (from `command_env()`), with
+/// `TMPDIR` (and `TEST_TMPDIR` for tests) set to `tmp_dir`, which is where
+/// the command sees `scratch.tmp()`. Tests also get `XML_OUTPUT_FILE`. In
+/// hermetic mode our own environment is cleared and `HOME` is set to
+/// `scratch.home()`. Variables in `env` take precedence.
+pub fn set_command_env(
+    command: &mut Command,
+    env: &HashMap<String, String>,
+    options: &EnvOptions,
+    scratch: &Scratch,
+    tmp_dir: &Path,
+    is_test: bool,
+) {
+    if options.hermetic {
+        command.env_clear();
+        command.env("HOME", scratch.home());
+    }
+    command.env("TMPDIR", tmp_dir);
+    if is_test {
+        command.env("TEST_TMPDIR", tmp_dir);
+        command.env("XML_OUTPUT_FILE", tmp_dir.join(JUNIT_OUTPUT_FILE));
+    }
+    command.envs(env);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn hermetic_env_includes_passed_variables() {
+        // Commands' fingerprints are hashes of this, so passed variables must
+        // be in it for changes to them to rerun commands and tests.
+        let name = format!("BUILD_EXACT_TEST_PASS_ENV_{}", process::id());
+        env::set_var(&name, "outside");
+        let options = EnvOptions {
+            hermetic: true,
+            pass_env: vec![name.clone(), "BUILD_EXACT_TEST_UNSET".to_string()],
+        };
+        let effective = command_env(&options, &vars(&[("LANG", "en_GB")]), &vars(&[("CC", "clang")]));
+        env::remove_var(&name);
+
+        let mut expected = vars(&[("PATH", "/usr/local/bin:/usr/bin:/bin"), ("LANG", "en_GB"), ("CC", "clang")]);
+        expected.insert(name, "outside".to_string());
+        assert_eq!(effective, expected);
+    }
+
+    #[test]
+    fn inherited_env_keeps_the_build_wide_variables() {
+        let options = EnvOptions {
+            hermetic: false,
+            pass_env: vec!["HOME".to_string()],
+        };
+        let effective = command_env(&options, &vars(&[("LANG", "en_GB"), ("CC", "gcc")]), &vars(&[("CC", "clang")]));
+        assert_eq!(effective, vars(&[("LANG", "en_GB"), ("CC", "clang")]));
+    }
+}
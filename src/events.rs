@@ -0,0 +1,105 @@
+use crate::sandbox::suggested_field;
+use crate::tracer::{Access, Violation};
+use anyhow::{Context, Result};
+use log::error;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Something that happened during the build. These are written as JSON
+/// lines to the `--json-events` file so that other tools can follow along.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum BuildEvent {
+    /// The build started. `violationsReported` is false if the sandbox
+    /// blocks some accesses without saying which (only `seccomp` reports
+    /// them), so commands may fail because of it without any
+    /// `sandboxViolation` events.
+    #[serde(rename_all = "camelCase")]
+    BuildStarted {
+        sandbox: String,
+        violations_reported: bool,
+    },
+    /// A command was started. It may turn out to be up to date.
+    #[serde(rename_all = "camelCase")]
+    CommandStarted {
+        key: String,
+        command: Vec<String>,
+    },
+    /// A command finished. `ran` is false if it was up to date, in which
+    /// case the other fields are missing. Commands that time out are killed
+    /// and are unsuccessful.
+    #[serde(rename_all = "camelCase")]
+    CommandFinished {
+        key: String,
+        ran: bool,
+        success: bool,
+        timed_out: bool,
+        exit_code: Option<i32>,
+        duration_ms: Option<u64>,
+        peak_rss_bytes: Option<u64>,
+        cpu_time_ms: Option<u64>,
+        error: Option<String>,
+    },
+    /// The sandbox blocked a command from accessing a file.
+    #[serde(rename_all = "camelCase")]
+    SandboxViolation {
+        key: String,
+        command: Vec<String>,
+        path: PathBuf,
+        access: Access,
+        syscall: String,
+        /// `inputs` or `outputs`, if there's a field the path could be
+        /// added to.
+        suggested_field: Option<&'static str>,
+    },
+}
+
+impl BuildEvent {
+    pub fn sandbox_violation(key: String, command: Vec<String>, violation: &Violation, is_test: bool) -> Self {
+        BuildEvent::SandboxViolation {
+            key,
+            command,
+            path: violation.path.clone(),
+            access: violation.access,
+            syscall: violation.syscall.clone(),
+            suggested_field: suggested_field(violation.access, is_test),
+        }
+    }
+}
+
+/// Where events go. If events aren't wanted, or writing them fails, they are
+/// dropped.
+#[derive(Default)]
+pub struct EventLog {
+    writer: Option<BufWriter<File>>,
+}
+
+impl EventLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Creating {:?}", path))?;
+        Ok(Self {
+            writer: Some(BufWriter::new(file)),
+        })
+    }
+
+    pub fn emit(&mut self, event: BuildEvent) {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        if let Err(e) = write_event(writer, &event) {
+            error!("Failed to write build event, no more will be written: {:?}", e);
+            self.writer = None;
+        }
+    }
+}
+
+fn write_event(writer: &mut BufWriter<File>, event: &BuildEvent) -> Result<()> {
+    serde_json::to_writer(&mut *writer, event)?;
+    writer.write_all(b"\n")?;
+    // Flush each event so that the file can be followed during the build.
+    writer.flush()?;
+    Ok(())
+}
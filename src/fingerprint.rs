@@ -0,0 +1,217 @@
+use crate::buildinfo::{BuildCommand, TestCommand};
+use crate::sandbox::SandboxKind;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Hash that we use for directories. Their contents are tracked separately
+/// (if they are declared at all) so we only care that they exist.
+const DIRECTORY_HASH: &str = "directory";
+
+/// Content hashes of everything that determines a command's result,
+/// recorded when it was last run. For a build command that is its outputs
+/// (with `--content-hash`). For a test it is whether it passes: a test that
+/// passed is skipped while these match, unless it has `no_cache`, or the
+/// build uses `--no-cache-test-results`, `--inherit-env` (since the
+/// inherited environment isn't hashed) or `--learn-deps`.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fingerprints {
+    /// Hash of the command line, working directory and environment (see
+    /// `hash_command()`). For tests it also covers the sandbox kind and
+    /// whether it had network access (see `hash_test_command()`).
+    pub command: String,
+    /// Map from input file to the hash of its contents. Files that didn't
+    /// exist are omitted.
+    pub inputs: HashMap<String, String>,
+    /// Map from output file to the hash of its contents. Files that didn't
+    /// exist are omitted. Tests don't have any.
+    pub outputs: HashMap<String, String>,
+}
+
+/// Hash the parts of a command that aren't files.
+pub fn hash_command(command: &BuildCommand) -> String {
+    let hasher = hash_invocation(&command.command, &command.working_dir, &command.env);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hash the parts of a test that aren't files, including how it is
+/// sandboxed and whether it has network access, since a test that passed
+/// with a looser sandbox might not pass with a stricter one.
+pub fn hash_test_command(command: &TestCommand, sandbox: SandboxKind, network: bool) -> String {
+    let mut hasher = hash_invocation(&command.command, &command.working_dir, &command.env);
+    let sandbox = format!("{:?}", sandbox);
+    hasher.update((sandbox.len() as u64).to_le_bytes());
+    hasher.update(sandbox.as_bytes());
+    hasher.update([network as u8]);
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_invocation(command: &[String], working_dir: &str, env: &HashMap<String, String>) -> Sha256 {
+    let mut hasher = Sha256::new();
+    // Length-prefix everything so that e.g. ["a b"] and ["a", "b"] differ.
+    for arg in command.iter() {
+        hasher.update((arg.len() as u64).to_le_bytes());
+        hasher.update(arg.as_bytes());
+    }
+    hasher.update((working_dir.len() as u64).to_le_bytes());
+    hasher.update(working_dir.as_bytes());
+    let mut env: Vec<(&String, &String)> = env.iter().collect();
+    env.sort();
+    for (name, value) in env {
+        hasher.update((name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value.as_bytes());
+    }
+    hasher
+}
+
+/// Hash the contents of a file. Returns `None` if it doesn't exist.
+pub fn hash_file(path: &Path) -> Result<Option<String>> {
+    let metadata = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading metadata for {:?}", path)),
+    };
+
+    if metadata.is_dir() {
+        return Ok(Some(DIRECTORY_HASH.to_owned()));
+    }
+
+    let mut file = File::open(path).with_context(|| format!("Opening {:?}", path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Reading {:?}", path))?;
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+/// Hash the contents of a set of files, omitting any that don't exist.
+pub fn hash_files(paths: &[String]) -> Result<HashMap<String, String>> {
+    let mut hashes = HashMap::with_capacity(paths.len());
+    for path in paths {
+        if let Some(hash) = hash_file(Path::new(path))? {
+            hashes.insert(path.clone(), hash);
+        }
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildinfo::ResourceLimits;
+    use tempfile::TempDir;
+
+    fn test_command(command: &[&str], env: &[(&str, &str)]) -> TestCommand {
+        TestCommand {
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            inputs: Vec::new(),
+            working_dir: "/src".to_string(),
+            env: env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            network: false,
+            limits: ResourceLimits::default(),
+            timeout: None,
+            no_cache: false,
+        }
+    }
+
+    #[test]
+    fn command_hash_covers_everything_but_files() {
+        let command = BuildCommand {
+            command: vec!["cc".to_string(), "-c".to_string(), "a.c".to_string()],
+            inputs: vec!["/src/a.c".to_string()],
+            outputs: vec!["/out/a.o".to_string()],
+            working_dir: "/src".to_string(),
+            env: [("LANG".to_string(), "C".to_string())].iter().cloned().collect(),
+            depfile: None,
+            dyndep: None,
+            network: false,
+            limits: ResourceLimits::default(),
+            timeout: None,
+        };
+        let hash = hash_command(&command);
+
+        // Files are fingerprinted separately.
+        let other_inputs = BuildCommand {
+            inputs: vec!["/src/b.c".to_string()],
+            ..command.clone()
+        };
+        assert_eq!(hash_command(&other_inputs), hash);
+
+        let other_args = BuildCommand {
+            command: vec!["cc".to_string(), "-c a.c".to_string()],
+            ..command.clone()
+        };
+        assert_ne!(hash_command(&other_args), hash);
+        let other_dir = BuildCommand {
+            working_dir: "/".to_string(),
+            ..command.clone()
+        };
+        assert_ne!(hash_command(&other_dir), hash);
+        let other_env = BuildCommand {
+            env: HashMap::new(),
+            ..command.clone()
+        };
+        assert_ne!(hash_command(&other_env), hash);
+    }
+
+    #[test]
+    fn test_command_hash_covers_how_it_runs() {
+        let command = test_command(&["./test", "--fast"], &[("LANG", "C")]);
+        let hash = hash_test_command(&command, SandboxKind::Seccomp, false);
+
+        assert_eq!(hash_test_command(&command.clone(), SandboxKind::Seccomp, false), hash);
+        assert_ne!(hash_test_command(&command, SandboxKind::None, false), hash);
+        assert_ne!(hash_test_command(&command, SandboxKind::Seccomp, true), hash);
+
+        let other_env = test_command(&["./test", "--fast"], &[("LANG", "C.UTF-8")]);
+        assert_ne!(hash_test_command(&other_env, SandboxKind::Seccomp, false), hash);
+
+        let other_dir = TestCommand {
+            working_dir: "/src/sub".to_string(),
+            ..command.clone()
+        };
+        assert_ne!(hash_test_command(&other_dir, SandboxKind::Seccomp, false), hash);
+
+        // Arguments are length-prefixed, so they can't run together.
+        let joined = test_command(&["./test --fast"], &[("LANG", "C")]);
+        assert_ne!(hash_test_command(&joined, SandboxKind::Seccomp, false), hash);
+    }
+
+    #[test]
+    fn test_command_hash_ignores_env_order() {
+        let command = test_command(&["./test"], &[("A", "1"), ("B", "2"), ("C", "3")]);
+        let reordered = test_command(&["./test"], &[("C", "3"), ("A", "1"), ("B", "2")]);
+        assert_eq!(
+            hash_test_command(&command, SandboxKind::External, false),
+            hash_test_command(&reordered, SandboxKind::External, false)
+        );
+    }
+
+    #[test]
+    fn hashes_file_contents() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        let file = dir.join("a.txt");
+        let missing = dir.join("missing.txt");
+
+        fs::write(&file, "one").unwrap();
+        let first = hash_file(&file).unwrap().unwrap();
+        fs::write(&file, "two").unwrap();
+        assert_ne!(hash_file(&file).unwrap().unwrap(), first);
+        fs::write(&file, "one").unwrap();
+        assert_eq!(hash_file(&file).unwrap().unwrap(), first);
+
+        assert_eq!(hash_file(&missing).unwrap(), None);
+        assert_eq!(hash_file(dir).unwrap().as_deref(), Some(DIRECTORY_HASH));
+
+        let paths = vec![file.to_string_lossy().into_owned(), missing.to_string_lossy().into_owned()];
+        let hashes = hash_files(&paths).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(hashes[&paths[0]], first);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::buildinfo::BuildInfo;
+use crate::fingerprint::hash_file;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Environment variable that tells external generators where to write the
+/// list of files that they read.
+pub const READ_FILES_ENV_VAR: &str = "BUILD_EXACT_READ_FILES";
+
+/// What a generator produced.
+pub struct GeneratorOutput {
+    pub build_info: BuildInfo,
+    /// Files that the generator read. These are used to decide whether it
+    /// needs to be rerun next time. Empty if the generator doesn't report
+    /// them.
+    pub read_files: Vec<String>,
+}
+
+/// Something that produces a `BuildInfo`. Generators can be slow, so their
+/// output is cached and they are only rerun when `hash_sources()` changes.
+pub trait Generator {
+    /// Hash everything that the generated BuildInfo depends on. `read_files`
+    /// are the files it reported reading last time it was run. Returns `None`
+    /// if that can't be determined, in which case it is always rerun.
+    fn hash_sources(&self, read_files: &[String]) -> Result<Option<String>>;
+
+    /// Run the generator.
+    fn run(&self) -> Result<GeneratorOutput>;
+}
+
+/// Any executable that prints `BuildInfo` JSON to stdout. It is passed the
+/// config file as its only argument.
+///
+/// If it wants to be cached it should also write the absolute paths of all
+/// the files it read (including the config file), one per line, to the file
+/// named by the `BUILD_EXACT_READ_FILES` environment variable. It will then
+/// only be rerun when one of those files changes.
+pub struct ExternalGenerator {
+    program: PathBuf,
+    config: PathBuf,
+    /// Where the generator writes the list of files it read.
+    read_files_path: PathBuf,
+}
+
+impl ExternalGenerator {
+    pub fn new(program: &Path, config: &Path, state_dir: &Path) -> Self {
+        Self {
+            program: program.to_owned(),
+            config: config.to_owned(),
+            read_files_path: state_dir.join("generator_read_files.txt"),
+        }
+    }
+}
+
+impl Generator for ExternalGenerator {
+    /// Also hashes the generator itself and how it is run, so that switching
+    /// to a different generator (or updating it) doesn't reuse the BuildInfo
+    /// that the old one produced from the same files.
+    fn hash_sources(&self, read_files: &[String]) -> Result<Option<String>> {
+        let read_files_hash = match hash_read_files(read_files)? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+
+        let program = find_program(&self.program);
+        let program_hash = hash_file(&program)?.unwrap_or_default();
+        let program = program.to_string_lossy();
+        let config = self.config.to_string_lossy();
+
+        let mut hasher = Sha256::new();
+        for part in [&*program, &program_hash, &*config, &read_files_hash].iter() {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    fn run(&self) -> Result<GeneratorOutput> {
+        // Make sure we don't pick up a stale list.
+        match fs::remove_file(&self.read_files_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let output = Command::new(&self.program)
+            .stderr(Stdio::inherit())
+            .env(READ_FILES_ENV_VAR, &self.read_files_path)
+            .arg(&self.config)
+            .output()
+            .with_context(|| format!("Running generator {:?}", self.program))?;
+
+        if !output.status.success() {
+            bail!(
+                "Generator {:?} failed with exit status: {}",
+                self.program,
+                output.status
+            );
+        }
+
+        let build_info: BuildInfo = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("Decoding output of generator {:?}", self.program))?;
+
+        let read_files = match fs::read_to_string(&self.read_files_path) {
+            Ok(contents) => contents.lines().filter(|line| !line.is_empty()).map(ToOwned::to_owned).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Reading {:?}", self.read_files_path)),
+        };
+
+        Ok(GeneratorOutput { build_info, read_files })
+    }
+}
+
+/// Find the executable that `Command` would run for `program`, which is
+/// looked up in `PATH` if it is just a name.
+fn find_program(program: &Path) -> PathBuf {
+    if program.components().count() != 1 {
+        return program.to_owned();
+    }
+    env::var_os("PATH")
+        .and_then(|paths| env::split_paths(&paths).map(|dir| dir.join(program)).find(|path| path.is_file()))
+        .unwrap_or_else(|| program.to_owned())
+}
+
+/// Hash the paths and contents of a list of files that a generator read.
+/// Returns `None` if the list is empty, since then we have no idea what it
+/// depends on.
+pub fn hash_read_files(read_files: &[String]) -> Result<Option<String>> {
+    if read_files.is_empty() {
+        return Ok(None);
+    }
+
+    let mut hasher = Sha256::new();
+    for file in read_files {
+        // Missing files hash differently to empty ones.
+        let file_hash = hash_file(Path::new(file))?.unwrap_or_default();
+        hasher.update(file.as_bytes());
+        hasher.update([0]);
+        hasher.update(file_hash.as_bytes());
+        hasher.update([0]);
+    }
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn cache_key_depends_on_the_generator() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        let config = dir.join("build.cfg");
+        let first = dir.join("first");
+        let second = dir.join("second");
+        fs::write(&config, "config").unwrap();
+        fs::write(&first, "#!/bin/sh").unwrap();
+        fs::write(&second, "#!/bin/sh").unwrap();
+        let read_files = vec![config.to_string_lossy().into_owned()];
+
+        let hash = |program: &Path| ExternalGenerator::new(program, &config, dir).hash_sources(&read_files).unwrap();
+
+        let original = hash(&first);
+        assert!(original.is_some());
+        assert_eq!(hash(&first), original);
+        assert_ne!(hash(&second), original);
+
+        fs::write(&first, "#!/bin/bash").unwrap();
+        assert_ne!(hash(&first), original);
+
+        // It can't be cached if it doesn't say what it read.
+        assert_eq!(ExternalGenerator::new(&first, &config, dir).hash_sources(&[]).unwrap(), None);
+    }
+}
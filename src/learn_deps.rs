@@ -0,0 +1,107 @@
+use crate::paths::with_canonical;
+use crate::tracer::{Access, FileAccess};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The difference between the files a command declared and the ones it
+/// actually used, found by tracing it with `--learn-deps`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LearnedDependencies {
+    /// The command line that was run.
+    pub command: Vec<String>,
+    /// Files it read that aren't inputs (or outputs).
+    pub undeclared_inputs: Vec<String>,
+    /// Files and directories it created or modified that aren't outputs.
+    pub undeclared_outputs: Vec<String>,
+    /// Inputs that it never accessed at all.
+    pub unused_inputs: Vec<String>,
+}
+
+/// An entry in the `--learn-deps` report.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LearnedCommand {
+    /// The key that identifies the command, as in the build state.
+    key: String,
+    #[serde(flatten)]
+    learned: LearnedDependencies,
+}
+
+/// The `--learn-deps` report for a whole build.
+#[derive(Default, Serialize)]
+pub struct LearnDepsReport {
+    commands: Vec<LearnedCommand>,
+}
+
+impl LearnDepsReport {
+    pub fn add(&mut self, key: String, learned: LearnedDependencies) {
+        self.commands.push(LearnedCommand { key, learned });
+    }
+
+    /// Write the report as JSON, sorted by key so that it is stable.
+    pub fn write(mut self, path: &Path) -> Result<()> {
+        self.commands.sort_by(|a, b| a.key.cmp(&b.key));
+        let json = serde_json::to_vec_pretty(&self)?;
+        fs::write(path, json).with_context(|| format!("Writing {:?}", path))?;
+        Ok(())
+    }
+}
+
+/// Compare the files a command accessed (in `sandboxed_dirs`, with symlinks
+/// resolved) with the ones it declared. This runs after the command has
+/// exited, so files that no longer exist are ignored: reading them was just
+/// probing, and writing them was a temporary file.
+pub fn compare(
+    command: &[String],
+    inputs: &[String],
+    outputs: &[String],
+    accesses: &[FileAccess],
+    sandboxed_dirs: &[String],
+) -> LearnedDependencies {
+    let inputs_resolved = with_canonical(inputs);
+    let outputs_resolved = with_canonical(outputs);
+    let under = |path: &Path, declared: &[PathBuf]| declared.iter().any(|d| path.starts_with(d));
+
+    let mut undeclared_inputs = Vec::new();
+    let mut undeclared_outputs = Vec::new();
+    for FileAccess { path, access } in accesses {
+        match access {
+            Access::Read => {
+                let is_file = fs::metadata(path).map(|m| m.is_file()).unwrap_or(false);
+                if is_file && !under(path, &inputs_resolved) && !under(path, &outputs_resolved) {
+                    undeclared_inputs.push(path.to_string_lossy().into_owned());
+                }
+            }
+            Access::Write => {
+                // Creating the directories that outputs go in is fine.
+                let is_output_parent = outputs_resolved.iter().any(|output| output.starts_with(path));
+                if fs::symlink_metadata(path).is_ok() && !under(path, &outputs_resolved) && !is_output_parent {
+                    undeclared_outputs.push(path.to_string_lossy().into_owned());
+                }
+            }
+            Access::Stat | Access::Network => {}
+        }
+    }
+
+    // Accesses outside the sandboxed directories aren't traced.
+    let sandboxed_dirs = with_canonical(sandboxed_dirs);
+    let unused_inputs = inputs
+        .iter()
+        .filter(|input| under(Path::new(input), &sandboxed_dirs))
+        .filter(|input| {
+            let declared = with_canonical(std::slice::from_ref(input));
+            !accesses.iter().any(|access| under(&access.path, &declared))
+        })
+        .cloned()
+        .collect();
+
+    LearnedDependencies {
+        command: command.to_vec(),
+        undeclared_inputs,
+        undeclared_outputs,
+        unused_inputs,
+    }
+}
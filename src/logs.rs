@@ -0,0 +1,162 @@
+use crate::dag::Target;
+use crate::state::test_command_key;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The file in the log directory that `LogIndex` is kept in.
+const INDEX_FILE: &str = "index.json";
+
+/// Which command's log each target refers to, as of the last build, so that
+/// `build_exact log` can find it without generating the build description
+/// again (which may run the generator, or need stdin).
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogIndex {
+    /// Map from each output file to the key of the command that generates it.
+    pub outputs: BTreeMap<String, String>,
+    /// The names of the tests.
+    pub tests: BTreeSet<String>,
+}
+
+impl LogIndex {
+    pub fn write(&self, log_dir: &Path) -> Result<()> {
+        fs::create_dir_all(log_dir).with_context(|| format!("Creating {:?}", log_dir))?;
+        let path = log_dir.join(INDEX_FILE);
+        fs::write(&path, serde_json::to_vec(self)?).with_context(|| format!("Writing {:?}", path))
+    }
+
+    /// The index written by the last build, or `None` if there hasn't been
+    /// one.
+    pub fn read(log_dir: &Path) -> Result<Option<Self>> {
+        let path = log_dir.join(INDEX_FILE);
+        match read_if_exists(&path)? {
+            Some(contents) => Ok(Some(serde_json::from_slice(&contents).with_context(|| format!("Parsing {:?}", path))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The key of the single command that a target refers to, which must be
+    /// `Output` or `Test`.
+    pub fn target_key(&self, target: &Target) -> Result<String> {
+        match target {
+            Target::Output(path) => match self.outputs.get(path) {
+                Some(key) => Ok(key.clone()),
+                None => bail!("No command generates output {:?}", path),
+            },
+            Target::Test(test) => {
+                if !self.tests.contains(test) {
+                    bail!("Test {} not found", test);
+                }
+                Ok(test_command_key(test))
+            }
+            _ => bail!("Expected an `output:<file>` or `test:<name>` target"),
+        }
+    }
+}
+
+/// The file that the output of the last run of the command with `key` (see
+/// `build_command_key()` and `test_command_key()`) is kept in. Keys can be
+/// long and contain slashes, so they are hashed.
+pub fn log_path(log_dir: &Path, key: &str) -> PathBuf {
+    log_dir.join(format!("{:x}.log", Sha256::digest(key.as_bytes())))
+}
+
+/// Send a command's stdout and stderr to its log file, replacing the output
+/// from its last run. They share the file so that they stay in order.
+pub fn log_output(command: &mut Command, log_dir: &Path, key: &str) -> Result<()> {
+    fs::create_dir_all(log_dir).with_context(|| format!("Creating {:?}", log_dir))?;
+    let path = log_path(log_dir, key);
+    let log = File::create(&path).with_context(|| format!("Creating {:?}", path))?;
+    command.stdout(log.try_clone()?).stderr(log);
+    Ok(())
+}
+
+/// The output from the last run of the command with `key`, or `None` if it
+/// has never been run.
+pub fn read_log(log_dir: &Path, key: &str) -> Result<Option<Vec<u8>>> {
+    read_if_exists(&log_path(log_dir, key))
+}
+
+/// The file that the JUnit XML written by the last run of the test with
+/// `key` is kept in, next to its log.
+fn junit_path(log_dir: &Path, key: &str) -> PathBuf {
+    log_path(log_dir, key).with_extension("xml")
+}
+
+/// Keep the JUnit XML that a test wrote to `written`, replacing the one from
+/// its last run, or removing that if it didn't write any this time.
+pub fn keep_junit(written: &Path, log_dir: &Path, key: &str) -> Result<()> {
+    let path = junit_path(log_dir, key);
+    match fs::copy(written, &path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).with_context(|| format!("Removing {:?}", path)),
+            _ => Ok(()),
+        },
+        Err(e) => Err(e).with_context(|| format!("Copying {:?} to {:?}", written, path)),
+    }
+}
+
+/// The JUnit XML written by the last run of the test with `key`, if any.
+pub fn read_junit(log_dir: &Path, key: &str) -> Result<Option<String>> {
+    let junit = read_if_exists(&junit_path(log_dir, key))?;
+    Ok(junit.map(|junit| String::from_utf8_lossy(&junit).into_owned()))
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {:?}", path)),
+    }
+}
+
+/// Print the output from the last run of the command with `key` to stderr
+/// as one block, so that it isn't interleaved with anything else. Nothing is
+/// printed if there was no output.
+pub fn print_log(log_dir: &Path, key: &str) -> Result<()> {
+    let output = read_log(log_dir, key)?.unwrap_or_default();
+    if output.is_empty() {
+        return Ok(());
+    }
+
+    let mut block = format!("---- Output of {} ----\n", key).into_bytes();
+    block.extend_from_slice(&output);
+    if !output.ends_with(b"\n") {
+        block.push(b'\n');
+    }
+    io::stderr().lock().write_all(&block)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn index_finds_targets_keys() {
+        let dir = TempDir::new().unwrap();
+        let log_dir = dir.path().join("logs");
+        assert!(LogIndex::read(&log_dir).unwrap().is_none());
+
+        let mut index = LogIndex::default();
+        index.outputs.insert("/out/a.o".to_string(), "outputs:/out/a.o:/out/a.d".to_string());
+        index.tests.insert("unit".to_string());
+        index.write(&log_dir).unwrap();
+        let index = LogIndex::read(&log_dir).unwrap().unwrap();
+
+        let key = |target: &str| index.target_key(&target.parse().unwrap());
+        assert_eq!(key("output:/out/a.o").unwrap(), "outputs:/out/a.o:/out/a.d");
+        assert_eq!(key("test:unit").unwrap(), "test:unit");
+        assert!(key("output:/out/b.o").is_err());
+        assert!(key("test:other").is_err());
+        assert!(key("output_all").is_err());
+    }
+}
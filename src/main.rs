@@ -1,87 +1,445 @@
 mod dag;
 mod dag_walker;
+mod depfile;
+mod environment;
+mod events;
 mod buildinfo;
 mod deno;
+mod fingerprint;
+mod generator;
 mod graphviz;
+mod learn_deps;
+mod logs;
+mod namespace_sandbox;
+mod paths;
+mod resources;
+mod sandbox;
+mod starlark_frontend;
+mod state;
+mod summary;
+mod test_report;
+mod tracer;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use dag::Target;
 use env_logger::Builder;
 use log::{info, warn};
-use std::path::PathBuf;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
 use structopt::StructOpt;
 
-use crate::dag::BuildDag;
+use crate::buildinfo::BuildInfo;
+use crate::dag::{BuildDag, BuildOptions};
+use crate::deno::DenoGenerator;
+use crate::environment::EnvOptions;
+use crate::events::{BuildEvent, EventLog};
+use crate::generator::{ExternalGenerator, Generator};
+use crate::logs::LogIndex;
+use crate::resources::CgroupParent;
+use crate::sandbox::SandboxKind;
+use crate::starlark_frontend::StarlarkGenerator;
+use crate::state::BuildState;
+use crate::test_report::TestReport;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "build_exact", about = "Build with exact dependency tracking.")]
 struct Opt {
-    /// Config file to build with (required)
+    /// Config file to build with (required). This can be a Deno script, a
+    /// Starlark `.star`/`BUILD` file, or BuildInfo JSON (`.json`, or `-` to
+    /// read it from stdin). Use `build_exact log <config> <target>` to print
+    /// the output from the last run of a command or test.
     #[structopt(parse(from_os_str))]
     config: PathBuf,
 
+    /// Generate the BuildInfo by running this executable with the config file
+    /// as its argument, instead of choosing a built-in generator from the
+    /// config file's extension.
+    #[structopt(long, parse(from_os_str))]
+    generator: Option<PathBuf>,
+
     /// RUST_LOG-style logging string, e.g. --log debug
     #[structopt(long)]
     log: Option<String>,
 
-    /// Disable the filesystem sandbox.
+    /// Disable the filesystem sandbox. Same as `--sandbox none`.
     #[structopt(long)]
     no_sandbox: bool,
 
+    /// How to sandbox commands: `external` (the `sandbox` binary built from
+    /// sandbox/), `landlock` (built in, needs Linux 5.13+), `namespace`
+    /// (built in, needs unprivileged user namespaces), `seccomp` (built in,
+    /// traces every path syscall; x86_64 and aarch64 only) or `none`. This
+    /// only affects files; see --allow-network.
+    #[structopt(long, default_value = "external")]
+    sandbox: SandboxKind,
+
+    /// Let every command use the network. By default only commands with
+    /// `network: true` can; the others get their own network namespace with
+    /// just a loopback interface, whatever the --sandbox (including `none`).
+    /// That needs unprivileged user namespaces; without them every command
+    /// can use the network, and an error is printed.
+    #[structopt(long)]
+    allow_network: bool,
+
+    /// Run each command in its own cgroup, created in this cgroup v2
+    /// directory, which we must be able to write to (e.g. delegated by
+    /// systemd). Memory and process limits then apply to the whole command,
+    /// and its resource usage is measured more accurately.
+    #[structopt(long, parse(from_os_str))]
+    cgroup: Option<PathBuf>,
+
+    /// Rerun every command under the tracer without sandboxing it, and write
+    /// a JSON report to this file of the undeclared inputs, undeclared
+    /// outputs and unused inputs of each one.
+    #[structopt(long, parse(from_os_str))]
+    learn_deps: Option<PathBuf>,
+
+    /// Decide what to rebuild by comparing the contents of files with the
+    /// hashes recorded in the last build, rather than by mtime. The first
+    /// build in this mode reruns everything.
+    #[structopt(long)]
+    content_hash: bool,
+
+    /// Run commands with the environment build_exact is run in (plus the
+    /// build description's `env` and their own), instead of a clean one.
+    /// Test results aren't reused with this, since any of that environment
+    /// could affect them.
+    #[structopt(long)]
+    inherit_env: bool,
+
+    /// Copy this variable from the environment build_exact is run in to
+    /// every command. Can be given more than once. This is in addition to the
+    /// build description's `passEnv`.
+    #[structopt(long, number_of_values = 1)]
+    pass_env: Vec<String>,
+
+    /// Write build events (commands starting and finishing, sandbox
+    /// violations, etc.) to this file as JSON lines. Only `--sandbox seccomp`
+    /// reports violations; with the other sandboxes commands that access
+    /// undeclared files just fail, and the first event says so.
+    #[structopt(long, parse(from_os_str))]
+    json_events: Option<PathBuf>,
+
+    /// Write the results of the tests that were run to this file, as JUnit
+    /// XML, or JSON if it ends in `.json`. Tests can write their own JUnit
+    /// XML to the file in `XML_OUTPUT_FILE`, and its test cases are included.
+    #[structopt(long, parse(from_os_str))]
+    test_report: Option<PathBuf>,
+
+    /// Rerun every requested test, instead of reusing the results of the
+    /// ones that passed last time when nothing they depend on has changed.
+    #[structopt(long)]
+    no_cache_test_results: bool,
+
     /// Visualise build graph
     #[structopt(long)]
     visualise: bool,
 
+    /// Number of commands to run in parallel. Defaults to the number of CPUs.
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+
+    /// Keep going until this many build commands have failed, or 0 to keep
+    /// going as long as anything can be built. Commands that depend on
+    /// failed ones are skipped either way.
+    #[structopt(short = "k", long, default_value = "1")]
+    keep_going: usize,
+
+    /// Print the output of every command and test that runs. By default it
+    /// is only printed if they fail, but it is always saved and can be
+    /// printed later with `build_exact log`.
+    #[structopt(short, long)]
+    verbose: bool,
+
     targets: Vec<Target>,
 }
 
-#[show_image::main]
+/// The name of the subcommand that prints a command's last output.
+const LOG_SUBCOMMAND: &str = "log";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "build_exact log", about = "Print the output from the last run of a command or test.")]
+struct LogOpt {
+    /// Config file that was built with. For `-` (stdin) this is only used
+    /// to find the state directory, which is in the current directory.
+    #[structopt(parse(from_os_str))]
+    config: PathBuf,
+
+    /// `output:<file>` for the command that generates the file, or
+    /// `test:<name>`.
+    target: Target,
+}
+
 fn main() -> Result<()> {
+    // The seccomp sandbox and `--learn-deps` run each command under this
+    // executable. Check for that before anything else so the tracer starts as
+    // a plain single-threaded process that doesn't need a display.
+    let args: Vec<_> = env::args_os().collect();
+    if args.get(1).map(|arg| arg.as_os_str()) == Some(OsStr::new(tracer::SUBCOMMAND)) {
+        process::exit(tracer::main(&args[2..])?);
+    }
+    if args.get(1).map(|arg| arg.as_os_str()) == Some(OsStr::new(LOG_SUBCOMMAND)) {
+        Builder::new().init();
+        return print_last_log(LogOpt::from_iter(&args[1..]));
+    }
+
     let opt = Opt::from_args();
 
-    Builder::new().parse_filters(&opt.log.unwrap_or_default()).init();
+    if opt.visualise {
+        // show_image needs to run its event loop on the main thread, so the
+        // build runs in another one.
+        show_image::run_context(move || build(opt));
+    }
+    build(opt)
+}
 
-    // 1. Run `deno info --unstable --json buildinfo.ts` to find the dependencies.
-    // 2. Check all their hashes.
-    // 3. Compare to the hash in the JSON.
-    // 4. If so re-run the deno command to regenerate the JSON.
+/// Run a build with the options from the command line.
+fn build(opt: Opt) -> Result<()> {
+    let rerun = rerun_command(&opt);
 
-    // 5. Build the DAG.
-    // 6. Run all the commands as needed.
+    Builder::new().parse_filters(&opt.log.unwrap_or_default()).init();
 
-    info!("Hashing buildinfo");
+    // 1. Ask the generator for the hash of its sources (e.g. for Deno run
+    //    `deno info --unstable --json buildinfo.ts` to find the dependencies
+    //    and concatenate their hashes).
+    // 2. Compare to the hash saved with the cached JSON.
+    // 3. If they differ re-run the generator to regenerate the JSON.
 
-    let _build_info_hash = deno::hash_buildinfo(&opt.config)?;
+    // 4. Build the DAG.
+    // 5. Run all the commands as needed.
 
-    info!("Running buildinfo");
+    let state_dir = state_dir(&opt.config);
+    let mut state = BuildState::load(&state_dir)?;
 
-    // TODO: We need some way of saving the build info hash.
-    // if build_info_hash != existing_hash {
-    let build_info = deno::run_buildinfo(&opt.config)?;
-    // }
+    let build_info = load_buildinfo(&opt.config, opt.generator.as_deref(), &state_dir, &mut state)?;
 
     info!("Building");
 
-    let dag = BuildDag::new(&build_info)?;
+    let mut pass_env = build_info.pass_env.clone();
+    pass_env.extend(opt.pass_env.iter().cloned());
+
+    let mut dag = BuildDag::new(build_info)?;
 
     if opt.targets.is_empty() {
         warn!("No targets selected, try adding `all`");
     }
 
-    dag.build(&opt.targets, opt.no_sandbox, opt.visualise)?;
+    let jobs = match opt.jobs {
+        Some(0) => bail!("--jobs must be at least 1"),
+        Some(jobs) => jobs,
+        None => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    };
+
+    let sandbox = if opt.learn_deps.is_some() {
+        SandboxKind::Learn
+    } else if opt.no_sandbox {
+        SandboxKind::None
+    } else {
+        opt.sandbox
+    };
+
+    sandbox::check_supported(sandbox)?;
+    // Commands are cut off from the network unless they opt in, but that
+    // needs user namespaces, and not having them shouldn't stop the build.
+    let allow_network = opt.allow_network || !sandbox::network_isolation_supported();
+
+    // Say if the events will be missing the violations.
+    let denies_nothing = sandbox == SandboxKind::None && allow_network;
+    let violations_reported = sandbox.reports_violations() || denies_nothing;
+    if opt.json_events.is_some() && !violations_reported {
+        warn!(
+            "--json-events won't include sandbox violations, because --sandbox {} doesn't report which \
+             accesses it denies. Use --sandbox seccomp to get them.",
+            sandbox
+        );
+    }
+
+    let options = BuildOptions {
+        sandbox,
+        execroot_dir: state_dir.join("execroot"),
+        content_hash: opt.content_hash,
+        visualise: opt.visualise,
+        jobs,
+        learn_deps: opt.learn_deps,
+        env: EnvOptions {
+            hermetic: !opt.inherit_env,
+            pass_env,
+        },
+        allow_network,
+        cgroup: opt.cgroup.as_deref().map(CgroupParent::new).transpose()?,
+        keep_going: opt.keep_going,
+        log_dir: state_dir.join("logs"),
+        verbose: opt.verbose,
+        cache_test_results: !opt.no_cache_test_results,
+    };
 
+    let mut events = match &opt.json_events {
+        Some(path) => EventLog::create(path)?,
+        None => EventLog::default(),
+    };
+    events.emit(BuildEvent::BuildStarted {
+        sandbox: sandbox.to_string(),
+        violations_reported,
+    });
+
+    let build_result = dag.build(&opt.targets, &options, &mut state, &mut events);
+
+    // Save the state even if the build failed so that we don't lose the
+    // information about the commands that did run.
+    state.save()?;
+    dag.log_index().write(&options.log_dir)?;
+
+    let summary = build_result?;
+    summary.print(&rerun);
+    if let Some(path) = &opt.test_report {
+        TestReport::new(&summary.tests, &options.log_dir)?.write(path)?;
+    }
+    if !summary.is_success() {
+        bail!("Build failed");
+    }
+    Ok(())
+}
+
+/// The command line to rerun failed tests with: the config file and the
+/// options that can affect whether they pass, but not the targets or options
+/// that only affect the output.
+fn rerun_command(opt: &Opt) -> String {
+    let mut args = vec!["build_exact".to_string(), opt.config.to_string_lossy().into_owned()];
+    if let Some(generator) = &opt.generator {
+        args.push("--generator".to_string());
+        args.push(generator.to_string_lossy().into_owned());
+    }
+    let sandbox = if opt.no_sandbox { SandboxKind::None } else { opt.sandbox };
+    if sandbox != SandboxKind::External {
+        args.push(format!("--sandbox={}", sandbox));
+    }
+    if opt.allow_network {
+        args.push("--allow-network".to_string());
+    }
+    if let Some(cgroup) = &opt.cgroup {
+        args.push("--cgroup".to_string());
+        args.push(cgroup.to_string_lossy().into_owned());
+    }
+    if opt.content_hash {
+        args.push("--content-hash".to_string());
+    }
+    if opt.inherit_env {
+        args.push("--inherit-env".to_string());
+    }
+    for name in opt.pass_env.iter() {
+        args.push(format!("--pass-env={}", name));
+    }
+    args.iter().map(|arg| summary::shell_quote(arg)).collect::<Vec<_>>().join(" ")
+}
+
+/// Print the output from the last run of the command or test that `target`
+/// refers to. The build description isn't needed; the last build recorded
+/// which command each target refers to.
+fn print_last_log(opt: LogOpt) -> Result<()> {
+    let log_dir = state_dir(&opt.config).join("logs");
+    let index = match LogIndex::read(&log_dir)? {
+        Some(index) => index,
+        None => bail!("{:?} hasn't been built yet", opt.config),
+    };
+
+    let key = index.target_key(&opt.target)?;
+    match logs::read_log(&log_dir, &key)? {
+        Some(output) => io::stdout().lock().write_all(&output)?,
+        None => bail!("{} hasn't been run yet", key),
+    }
     Ok(())
 }
 
+/// Get the BuildInfo for `config`, from `generator` if there is one, and
+/// otherwise by reading it or choosing a generator from its extension.
+fn load_buildinfo(config: &Path, generator: Option<&Path>, state_dir: &Path, state: &mut BuildState) -> Result<BuildInfo> {
+    if let Some(program) = generator {
+        let generator = ExternalGenerator::new(program, config, state_dir);
+        load_generated_buildinfo(config, &generator, state)
+    } else if is_json_config(config) {
+        info!("Reading buildinfo JSON");
+        read_json_buildinfo(config)
+    } else if starlark_frontend::is_starlark_file(config) {
+        let generator = StarlarkGenerator::new(config);
+        load_generated_buildinfo(config, &generator, state)
+    } else {
+        let generator = DenoGenerator::new(config);
+        load_generated_buildinfo(config, &generator, state)
+    }
+}
+
+/// Returns true if `config` is BuildInfo JSON that we can read directly
+/// rather than a script that generates it.
+fn is_json_config(config: &Path) -> bool {
+    config == Path::new("-") || config.extension() == Some(OsStr::new("json"))
+}
+
+/// Read BuildInfo JSON from a file, or from stdin if `config` is `-`. This
+/// lets any tool generate the build description, without needing Deno.
+fn read_json_buildinfo(config: &Path) -> Result<BuildInfo> {
+    let build_info = if config == Path::new("-") {
+        serde_json::from_reader(io::stdin().lock()).context("Parsing buildinfo JSON from stdin")?
+    } else {
+        let file = File::open(config).with_context(|| format!("Opening {:?}", config))?;
+        serde_json::from_reader(BufReader::new(file)).with_context(|| format!("Parsing {:?}", config))?
+    };
+    Ok(build_info)
+}
+
+/// Get the BuildInfo from a generator, or from the cache if none of its
+/// sources have changed since last time.
+fn load_generated_buildinfo(config: &Path, generator: &dyn Generator, state: &mut BuildState) -> Result<BuildInfo> {
+    info!("Hashing buildinfo");
+
+    let previous_read_files = state.buildinfo_read_files(config)?;
+    let build_info_hash = generator.hash_sources(&previous_read_files)?;
+
+    if let Some(build_info_hash) = &build_info_hash {
+        if let Some(build_info) = state.cached_buildinfo(config, build_info_hash)? {
+            info!("Buildinfo sources are unchanged; using cached buildinfo");
+            state.buildinfo_hash = Some(build_info_hash.clone());
+            return Ok(build_info);
+        }
+    }
+
+    info!("Running buildinfo");
+    let output = generator.run()?;
+
+    // The generator may have read different files this time.
+    let build_info_hash = if output.read_files == previous_read_files {
+        build_info_hash
+    } else {
+        generator.hash_sources(&output.read_files)?
+    };
+
+    if let Some(build_info_hash) = &build_info_hash {
+        state.save_buildinfo(config, build_info_hash, &output.build_info, &output.read_files)?;
+    }
+    state.buildinfo_hash = build_info_hash;
+    Ok(output.build_info)
+}
+
+/// The directory that we store information between builds in. It is next to
+/// the config file.
+fn state_dir(config: &Path) -> PathBuf {
+    config.parent().unwrap_or_else(|| Path::new("")).join(".build_exact")
+}
+
 
 // TODO:
 
 // 1. Switch to Starlark Rust: https://github.com/facebookexperimental/starlark-rust
+//    Done for `.star`/`BUILD` files; see starlark_frontend.rs.
 // 2. Dynamic dependency discovery. Basically when we depend on certain
 //    files (e.g. foo.cpp) we run a rule on them first that will determine
 //    their dependencies.
 //    Nah that's tricky because the rule must be able to do anything so
 //    the whole thing is no longer hermetic.
 //  So scratch that, we'll just use Typescript.
-//
-// Also, 3: Use SQLite for storing build info.
+//  Depfiles and dyndep files now cover the common cases though; see
+//  depfile.rs and `BuildCommand::dyndep`.
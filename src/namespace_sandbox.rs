@@ -0,0 +1,511 @@
+use crate::sandbox::SandboxAccess;
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Used to give each command its own execroot.
+static NEXT_EXECROOT: AtomicUsize = AtomicUsize::new(0);
+
+/// A private directory tree that is mounted over the sandboxed directories
+/// while a command runs. It only contains the command's inputs (bind mounted
+/// read-only) and the directories its outputs go in. The outputs are written
+/// into it and then moved to their real locations by `finish()`.
+pub struct Execroot {
+    dir: PathBuf,
+    /// Where each output is written in the execroot, and where it belongs.
+    outputs: Vec<(PathBuf, PathBuf)>,
+    /// Whether the command's scratch `tmp` directory is mounted on `/tmp`.
+    private_tmp: bool,
+}
+
+/// The contents of `/proc/self/uid_map` and `gid_map` for a new user
+/// namespace, which map our own user and group so that file ownership looks
+/// normal.
+struct IdMaps {
+    uid_map: CString,
+    gid_map: CString,
+}
+
+impl IdMaps {
+    fn new() -> Result<Self> {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(Self {
+            uid_map: CString::new(format!("{} {} 1", uid, uid))?,
+            gid_map: CString::new(format!("{} {} 1", gid, gid))?,
+        })
+    }
+}
+
+/// Everything the child needs to set up its mount namespace. It is all
+/// prepared in the parent so that the child doesn't need to allocate.
+struct MountPlan {
+    ids: IdMaps,
+    /// Whether to enter a new network namespace too.
+    isolate_network: bool,
+    /// Inputs to bind mount into the execroot: source, target, and the flags
+    /// to remount it read-only with.
+    binds: Vec<(CString, CString, libc::c_ulong)>,
+    /// Execroot directories to mount over the sandboxed directories.
+    roots: Vec<(CString, CString)>,
+    /// The directory to mount on `/tmp`, if any.
+    private_tmp: Option<PrivateTmp>,
+    /// The working directory, which has to be entered again after the
+    /// sandboxed directories are replaced.
+    working_dir: CString,
+}
+
+/// A directory to mount on `/tmp`, and the paths under `/tmp` that must
+/// still be visible afterwards. Those are bind mounted into it first, at the
+/// same place relative to `/tmp`.
+struct PrivateTmp {
+    dir: CString,
+    keep: Vec<(CString, CString)>,
+}
+
+/// Set up `command` to run in new user and mount namespaces in which the
+/// sandboxed directories are replaced by an execroot containing only the
+/// inputs and outputs in `access`. Unlike path-based sandboxes this can't be
+/// bypassed with symlinks or unusual syscalls, because the files simply
+/// aren't there.
+///
+/// Outputs start off missing (existing ones aren't copied in), and
+/// directories that contain outputs are created empty rather than mounted
+/// even if they are also inputs. `/tmp` is replaced by the command's scratch
+/// `tmp` directory, except for the sandboxed directories, the working
+/// directory and the scratch `HOME` (anything else in the real `/tmp` is
+/// hidden). Unless `access.network` is set the command also gets its own
+/// network namespace, like `isolate_network()`.
+pub fn prepare(command: &mut Command, working_dir: &Path, access: &SandboxAccess, execroot_dir: &Path) -> Result<Execroot> {
+    // Other build_exact processes may be using the same state directory.
+    let id = NEXT_EXECROOT.fetch_add(1, Ordering::Relaxed);
+    let dir = execroot_dir.join(format!("{}-{}", process::id(), id));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).with_context(|| format!("Removing stale execroot {:?}", dir))?;
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("Creating {:?}", dir))?;
+    // The paths are used after the child changes directory.
+    let dir = fs::canonicalize(&dir)?;
+
+    let sandboxed_dirs: Vec<&Path> = access.sandboxed_dirs.iter().map(Path::new).collect();
+
+    // Each sandboxed directory gets its own directory in the execroot.
+    let execroot_path = |path: &Path| -> Option<PathBuf> {
+        sandboxed_dirs.iter().enumerate().find_map(|(i, sandboxed)| {
+            path.strip_prefix(sandboxed).ok().map(|relative| dir.join(i.to_string()).join(relative))
+        })
+    };
+
+    let mut roots = Vec::new();
+    for (i, sandboxed) in sandboxed_dirs.iter().enumerate() {
+        if !sandboxed.is_dir() {
+            continue;
+        }
+        let root = dir.join(i.to_string());
+        fs::create_dir_all(&root).with_context(|| format!("Creating {:?}", root))?;
+        roots.push((c_path(&root)?, c_path(sandboxed)?));
+    }
+
+    let mut outputs = Vec::new();
+    for output in access.write {
+        let output = Path::new(output);
+        if let Some(inside) = execroot_path(output) {
+            if let Some(parent) = inside.parent() {
+                fs::create_dir_all(parent).with_context(|| format!("Creating {:?}", parent))?;
+            }
+            outputs.push((inside, output.to_owned()));
+        }
+    }
+
+    if let Some(inside) = execroot_path(working_dir) {
+        fs::create_dir_all(&inside).with_context(|| format!("Creating {:?}", inside))?;
+    }
+
+    // Mount parents before children.
+    let mut inputs: Vec<&Path> = access.read.iter().map(Path::new).collect();
+    inputs.sort_by_key(|input| input.as_os_str().len());
+
+    let mut binds = Vec::new();
+    for input in inputs {
+        let inside = match execroot_path(input) {
+            Some(inside) => inside,
+            None => continue,
+        };
+        // Inputs that don't exist, e.g. headers that a depfile listed last
+        // time but that have since been deleted, have nothing to mount. The
+        // command is rerun because they are missing, and fails if it still
+        // needs them.
+        let metadata = match fs::metadata(input) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Reading input {:?}", input)),
+        };
+        if metadata.is_dir() {
+            fs::create_dir_all(&inside).with_context(|| format!("Creating {:?}", inside))?;
+            if outputs.iter().any(|(_, output)| output.starts_with(input)) {
+                continue;
+            }
+        } else {
+            if let Some(parent) = inside.parent() {
+                fs::create_dir_all(parent).with_context(|| format!("Creating {:?}", parent))?;
+            }
+            fs::File::create(&inside).with_context(|| format!("Creating {:?}", inside))?;
+        }
+        binds.push((c_path(input)?, c_path(&inside)?, read_only_remount_flags(input)?));
+    }
+
+    let private_tmp = private_tmp(&sandboxed_dirs, working_dir, access)?;
+    let has_private_tmp = private_tmp.is_some();
+
+    let plan = MountPlan {
+        ids: IdMaps::new()?,
+        isolate_network: !access.network,
+        binds,
+        roots,
+        private_tmp,
+        working_dir: c_path(working_dir)?,
+    };
+
+    // Safety: `enter_sandbox` only makes async-signal-safe syscalls.
+    unsafe {
+        command.pre_exec(move || enter_sandbox(&plan));
+    }
+
+    Ok(Execroot {
+        dir,
+        outputs,
+        private_tmp: has_private_tmp,
+    })
+}
+
+/// Plan mounting the scratch `tmp` directory on `/tmp`, creating the mount
+/// points for the paths that have to stay visible. If `/tmp` is in a
+/// sandboxed directory then the execroot already replaces it, so it is left
+/// alone.
+fn private_tmp(sandboxed_dirs: &[&Path], working_dir: &Path, access: &SandboxAccess) -> Result<Option<PrivateTmp>> {
+    let tmp = Path::new("/tmp");
+    if sandboxed_dirs.iter().any(|sandboxed| tmp.starts_with(sandboxed)) {
+        return Ok(None);
+    }
+
+    let home = access.scratch.home();
+    let mut wanted: Vec<&Path> = sandboxed_dirs.iter().copied().filter(|dir| dir.is_dir()).collect();
+    wanted.push(working_dir);
+    wanted.push(&home);
+    wanted.retain(|path| path.starts_with(tmp));
+    wanted.sort_by_key(|path| path.as_os_str().len());
+
+    // Mounting a directory brings everything mounted inside it along.
+    let mut keep: Vec<&Path> = Vec::new();
+    for path in wanted {
+        if !keep.iter().any(|kept| path.starts_with(kept)) {
+            keep.push(path);
+        }
+    }
+
+    let dir = fs::canonicalize(access.scratch.tmp())?;
+    let mut keep_mounts = Vec::new();
+    for path in keep {
+        let target = dir.join(path.strip_prefix(tmp)?);
+        fs::create_dir_all(&target).with_context(|| format!("Creating {:?}", target))?;
+        keep_mounts.push((c_path(path)?, c_path(&target)?));
+    }
+
+    Ok(Some(PrivateTmp {
+        dir: c_path(&dir)?,
+        keep: keep_mounts,
+    }))
+}
+
+impl Execroot {
+    pub fn has_private_tmp(&self) -> bool {
+        self.private_tmp
+    }
+
+    /// Move the outputs that the command wrote to their real locations, and
+    /// delete the execroot. This must be called after the command exits,
+    /// whether or not it succeeded.
+    pub fn finish(self) -> Result<()> {
+        for (inside, output) in self.outputs.iter() {
+            if fs::symlink_metadata(inside).is_ok() {
+                move_output(inside, output)?;
+            }
+        }
+        fs::remove_dir_all(&self.dir).with_context(|| format!("Removing execroot {:?}", self.dir))?;
+        Ok(())
+    }
+}
+
+/// Move a file or directory, merging directories into ones that already
+/// exist.
+fn move_output(from: &Path, to: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.is_dir() {
+        fs::create_dir_all(to).with_context(|| format!("Creating {:?}", to))?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            move_output(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        return Ok(());
+    }
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Creating {:?}", parent))?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        // The execroot is on a different filesystem.
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            fs::copy(from, to).with_context(|| format!("Copying output {:?} to {:?}", from, to))?;
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("Moving output {:?} to {:?}", from, to)),
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// The flags needed to remount a bind mount of `path` read-only. In a user
+/// namespace the flags of the original mount are locked, so they have to be
+/// kept.
+fn read_only_remount_flags(path: &Path) -> Result<libc::c_ulong> {
+    let c_path = c_path(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("Reading mount flags of {:?}", path));
+    }
+
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+    for (st_flag, ms_flag) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st_flag != 0 {
+            flags |= ms_flag;
+        }
+    }
+    Ok(flags)
+}
+
+/// Set up `command` to run in new user and network namespaces, so that it
+/// can only use the loopback interface.
+pub fn isolate_network(command: &mut Command) -> Result<()> {
+    let ids = IdMaps::new()?;
+    // Safety: `enter_network_namespace` only makes async-signal-safe
+    // syscalls.
+    unsafe {
+        command.pre_exec(move || enter_network_namespace(&ids));
+    }
+    Ok(())
+}
+
+/// This runs in the child after fork, so it must only make raw syscalls.
+fn enter_network_namespace(ids: &IdMaps) -> io::Result<()> {
+    unsafe {
+        check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET))?;
+        write_id_maps(ids)?;
+        bring_up_loopback()
+    }
+}
+
+/// Enter the new namespaces and set up the mounts. This runs in the child
+/// after fork, so it must only make raw syscalls.
+fn enter_sandbox(plan: &MountPlan) -> io::Result<()> {
+    unsafe {
+        let mut namespaces = libc::CLONE_NEWUSER | libc::CLONE_NEWNS;
+        if plan.isolate_network {
+            namespaces |= libc::CLONE_NEWNET;
+        }
+        check(libc::unshare(namespaces))?;
+        write_id_maps(&plan.ids)?;
+        if plan.isolate_network {
+            bring_up_loopback()?;
+        }
+
+        // Don't let our mounts propagate back to the parent namespace.
+        check(libc::mount(
+            ptr::null(),
+            b"/\0".as_ptr().cast(),
+            ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            ptr::null(),
+        ))?;
+
+        for (source, target, read_only_flags) in plan.binds.iter() {
+            check(libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                ptr::null(),
+            ))?;
+            check(libc::mount(
+                ptr::null(),
+                target.as_ptr(),
+                ptr::null(),
+                *read_only_flags,
+                ptr::null(),
+            ))?;
+        }
+
+        for (root, sandboxed_dir) in plan.roots.iter() {
+            check(libc::mount(
+                root.as_ptr(),
+                sandboxed_dir.as_ptr(),
+                ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                ptr::null(),
+            ))?;
+        }
+
+        if let Some(private_tmp) = &plan.private_tmp {
+            for (path, target) in private_tmp.keep.iter() {
+                check(libc::mount(
+                    path.as_ptr(),
+                    target.as_ptr(),
+                    ptr::null(),
+                    libc::MS_BIND | libc::MS_REC,
+                    ptr::null(),
+                ))?;
+            }
+            check(libc::mount(
+                private_tmp.dir.as_ptr(),
+                b"/tmp\0".as_ptr().cast(),
+                ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                ptr::null(),
+            ))?;
+        }
+
+        // The old working directory is now hidden underneath the mounts.
+        check(libc::chdir(plan.working_dir.as_ptr()))?;
+    }
+    Ok(())
+}
+
+/// Map our own user and group in a new user namespace.
+unsafe fn write_id_maps(ids: &IdMaps) -> io::Result<()> {
+    write_file(b"/proc/self/setgroups\0", b"deny")?;
+    write_file(b"/proc/self/uid_map\0", ids.uid_map.as_bytes())?;
+    write_file(b"/proc/self/gid_map\0", ids.gid_map.as_bytes())
+}
+
+/// A new network namespace only has a loopback interface, and it starts off
+/// down.
+unsafe fn bring_up_loopback() -> io::Result<()> {
+    let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut request: libc::ifreq = std::mem::zeroed();
+    for (to, from) in request.ifr_name.iter_mut().zip(b"lo\0") {
+        *to = *from as libc::c_char;
+    }
+    let mut result = libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut request);
+    if result == 0 {
+        request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        result = libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &request);
+    }
+    let error = io::Error::last_os_error();
+    libc::close(fd);
+    if result != 0 {
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Write `contents` to the nul-terminated `path`.
+unsafe fn write_file(path: &[u8], contents: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr().cast(), libc::O_WRONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+    libc::close(fd);
+    if written != contents.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns an error if we can't create a user namespace along with
+/// `namespaces`, so that we can give a better error before the build starts
+/// than every command failing with EPERM. As well as with
+/// `user.max_user_namespaces`, unprivileged user namespaces can be disabled
+/// with `kernel.unprivileged_userns_clone` or restricted by AppArmor, so this
+/// tries to set them up in a child process. `alternative` says what to do
+/// instead.
+pub fn check_supported(namespaces: libc::c_int, alternative: &str) -> Result<()> {
+    let max = fs::read_to_string("/proc/sys/user/max_user_namespaces").unwrap_or_default();
+    if max.trim() == "0" {
+        bail!("User namespaces are disabled (user.max_user_namespaces is 0). {}", alternative);
+    }
+    if let Err(e) = probe(namespaces, &IdMaps::new()?) {
+        bail!(
+            "Can't set up a user namespace: {}. Unprivileged user namespaces may be disabled \
+             (kernel.unprivileged_userns_clone) or restricted by AppArmor \
+             (kernel.apparmor_restrict_unprivileged_userns). {}",
+            e,
+            alternative
+        );
+    }
+    Ok(())
+}
+
+/// Enter new namespaces in a child process, as commands will, and report how
+/// that went.
+fn probe(namespaces: libc::c_int, ids: &IdMaps) -> io::Result<()> {
+    unsafe {
+        let pid = libc::fork();
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if pid == 0 {
+            // Only raw syscalls from here on, since we may have been forked
+            // from a multithreaded process.
+            let mut result = check(libc::unshare(libc::CLONE_NEWUSER | namespaces));
+            if result.is_ok() {
+                result = write_id_maps(ids);
+            }
+            if result.is_ok() && namespaces & libc::CLONE_NEWNET != 0 {
+                result = bring_up_loopback();
+            }
+            let code = match result {
+                Ok(()) => 0,
+                Err(e) => e.raw_os_error().unwrap_or(libc::EPERM),
+            };
+            libc::_exit(code);
+        }
+
+        let mut status = 0;
+        if libc::waitpid(pid, &mut status, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if !libc::WIFEXITED(status) {
+            return Err(io::Error::other("the probe process was killed"));
+        }
+        match libc::WEXITSTATUS(status) {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Remove `.` and `..` components from an absolute path without touching the
+/// filesystem.
+pub fn normalise(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalised.pop();
+            }
+            _ => normalised.push(component),
+        }
+    }
+    normalised
+}
+
+/// The paths, plus their canonical versions where those are different, so
+/// that paths the kernel reports with symlinks resolved still match them.
+pub fn with_canonical(paths: &[String]) -> Vec<PathBuf> {
+    let mut all = Vec::with_capacity(paths.len());
+    for path in paths {
+        let path = PathBuf::from(path);
+        if let Ok(canonical) = fs::canonicalize(&path) {
+            if canonical != path {
+                all.push(canonical);
+            }
+        }
+        all.push(path);
+    }
+    all
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_removes_dot_components() {
+        assert_eq!(normalise(Path::new("/src/./a/../b/foo.h")), PathBuf::from("/src/b/foo.h"));
+        assert_eq!(normalise(Path::new("/src/../../foo.h")), PathBuf::from("/foo.h"));
+    }
+}
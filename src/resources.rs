@@ -0,0 +1,467 @@
+use crate::buildinfo::ResourceLimits;
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The controllers that commands' cgroups use for limits.
+const CGROUP_CONTROLLERS: &[&str] = &["memory", "pids"];
+
+/// How long a command that has timed out gets to exit after SIGTERM, before
+/// it is sent SIGKILL.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Used to give each command its own cgroup.
+static NEXT_CGROUP: AtomicUsize = AtomicUsize::new(0);
+
+/// The process groups of the commands that are running with a timeout.
+static PROCESS_GROUPS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+
+/// Set up by `forward_signals()` the first time that a command is run with
+/// a timeout.
+static FORWARD_SIGNALS: Once = Once::new();
+
+/// The end of a pipe that the SIGINT and SIGTERM handler writes to.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// The resources that a command used, including its child processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceUsage {
+    /// The largest resident set size of any one process, or of the whole
+    /// cgroup if the command had one.
+    pub peak_rss_bytes: u64,
+    /// User plus system CPU time.
+    pub cpu_time: Duration,
+}
+
+/// A cgroup v2 directory that we can create commands' cgroups in
+/// (`--cgroup`), e.g. one delegated to us by systemd.
+#[derive(Clone)]
+pub struct CgroupParent {
+    dir: PathBuf,
+}
+
+impl CgroupParent {
+    /// Use `dir`, enabling the controllers that commands' cgroups use if
+    /// they are available and aren't enabled already. That fails if there
+    /// are processes directly in `dir`, so it should be a cgroup of its own.
+    pub fn new(dir: &Path) -> Result<Self> {
+        let controllers_file = dir.join("cgroup.controllers");
+        let controllers = fs::read_to_string(&controllers_file)
+            .with_context(|| format!("{:?} isn't a cgroup v2 directory", dir))?;
+        let subtree_file = dir.join("cgroup.subtree_control");
+        let enabled = fs::read_to_string(&subtree_file).with_context(|| format!("Reading {:?}", subtree_file))?;
+
+        for controller in CGROUP_CONTROLLERS {
+            let available = controllers.split_whitespace().any(|c| c == *controller);
+            if !available || enabled.split_whitespace().any(|c| c == *controller) {
+                continue;
+            }
+            fs::write(&subtree_file, format!("+{}", controller))
+                .with_context(|| format!("Enabling the {} controller in {:?}", controller, subtree_file))?;
+        }
+
+        Ok(Self { dir: dir.to_owned() })
+    }
+
+    /// Create a cgroup for a single command, with the `limits` that cgroups
+    /// enforce. It is removed when the `Cgroup` is dropped.
+    pub fn create(&self, limits: &ResourceLimits) -> Result<Cgroup> {
+        let id = NEXT_CGROUP.fetch_add(1, Ordering::Relaxed);
+        let dir = self.dir.join(format!("build_exact-{}-{}", process::id(), id));
+        fs::create_dir(&dir).with_context(|| format!("Creating cgroup {:?}", dir))?;
+        let cgroup = Cgroup {
+            procs: CString::new(dir.join("cgroup.procs").as_os_str().as_bytes())?,
+            dir,
+        };
+
+        if let Some(memory_bytes) = limits.memory_bytes {
+            cgroup.write("memory.max", memory_bytes)?;
+            // Otherwise it just starts swapping.
+            if cgroup.dir.join("memory.swap.max").exists() {
+                cgroup.write("memory.swap.max", 0)?;
+            }
+        }
+        if let Some(processes) = limits.processes {
+            cgroup.write("pids.max", processes)?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// A cgroup that a single command runs in.
+pub struct Cgroup {
+    dir: PathBuf,
+    /// The `cgroup.procs` file, which the child writes itself to.
+    procs: CString,
+}
+
+impl Cgroup {
+    fn write(&self, file: &str, value: u64) -> Result<()> {
+        let path = self.dir.join(file);
+        fs::write(&path, value.to_string())
+            .with_context(|| format!("Writing {:?} (is its controller available in the parent cgroup?)", path))
+    }
+
+    /// Replace `usage` with the cgroup's own measurements where the kernel
+    /// has them, since they include processes that weren't waited for.
+    pub fn measured(&self, usage: ResourceUsage) -> ResourceUsage {
+        let peak_rss_bytes = fs::read_to_string(self.dir.join("memory.peak"))
+            .ok()
+            .and_then(|peak| peak.trim().parse().ok())
+            .unwrap_or(usage.peak_rss_bytes);
+        let cpu_time = fs::read_to_string(self.dir.join("cpu.stat"))
+            .ok()
+            .and_then(|stat| {
+                stat.lines()
+                    .find_map(|line| line.strip_prefix("usage_usec "))
+                    .and_then(|usec| usec.trim().parse().ok())
+            })
+            .map(Duration::from_micros)
+            .unwrap_or(usage.cpu_time);
+        ResourceUsage {
+            peak_rss_bytes,
+            cpu_time,
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    /// Kill anything the command left running, then remove the cgroup. The
+    /// processes take a moment to go away.
+    fn drop(&mut self) {
+        let _ = fs::write(self.dir.join("cgroup.kill"), "1");
+        for _ in 0..100 {
+            match fs::remove_dir(&self.dir) {
+                Ok(()) => return,
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => thread::sleep(Duration::from_millis(10)),
+                Err(e) => {
+                    warn!("Failed to remove cgroup {:?}: {}", self.dir, e);
+                    return;
+                }
+            }
+        }
+        warn!("Failed to remove cgroup {:?}: it still has processes in it", self.dir);
+    }
+}
+
+/// Set up `command` to move itself into `cgroup` (if any) and apply the
+/// `limits` with `setrlimit` before it execs. Memory and process limits are
+/// left to the cgroup if there is one.
+pub fn apply_limits(command: &mut Command, limits: &ResourceLimits, cgroup: Option<&Cgroup>) -> Result<()> {
+    let mut rlimits = Vec::new();
+    rlimits.extend(limits.cpu_seconds.map(|seconds| (libc::RLIMIT_CPU, seconds)));
+    rlimits.extend(limits.open_files.map(|files| (libc::RLIMIT_NOFILE, files)));
+    if cgroup.is_none() {
+        rlimits.extend(limits.memory_bytes.map(|bytes| (libc::RLIMIT_AS, bytes)));
+        rlimits.extend(limits.processes.map(|processes| (libc::RLIMIT_NPROC, processes)));
+    }
+
+    // Check the limits here, because the child can't raise its hard limits
+    // and couldn't give a useful error.
+    let mut rlimits_to_set = Vec::with_capacity(rlimits.len());
+    for (resource, value) in rlimits {
+        let mut current: libc::rlimit = unsafe { mem::zeroed() };
+        if unsafe { libc::getrlimit(resource, &mut current) } != 0 {
+            return Err(io::Error::last_os_error()).context("Reading resource limits");
+        }
+        if value > current.rlim_max {
+            bail!(
+                "Resource limit {} is higher than build_exact's own hard limit of {}",
+                value,
+                current.rlim_max
+            );
+        }
+        rlimits_to_set.push((
+            resource,
+            libc::rlimit {
+                rlim_cur: value,
+                rlim_max: value,
+            },
+        ));
+    }
+
+    let procs = cgroup.map(|cgroup| cgroup.procs.clone());
+    if rlimits_to_set.is_empty() && procs.is_none() {
+        return Ok(());
+    }
+
+    // Safety: `enter_limits` only makes async-signal-safe syscalls.
+    unsafe {
+        command.pre_exec(move || enter_limits(&rlimits_to_set, procs.as_ref()));
+    }
+    Ok(())
+}
+
+/// This runs in the child after fork, so it must only make raw syscalls.
+fn enter_limits(rlimits: &[(libc::__rlimit_resource_t, libc::rlimit)], procs: Option<&CString>) -> io::Result<()> {
+    unsafe {
+        if let Some(procs) = procs {
+            // Writing 0 moves the writing process.
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+            libc::close(fd);
+            if written != 1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (resource, limit) in rlimits {
+            if libc::setrlimit(*resource, limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What happened when a command was run by `run_to_completion()`.
+pub struct Completed {
+    pub status: ExitStatus,
+    pub usage: ResourceUsage,
+    /// Whether it was killed because it ran for longer than its timeout.
+    pub timed_out: bool,
+}
+
+/// Like `Command::status()`, but also returns the resources that the
+/// command used, and kills it if it runs for longer than `timeout`. Its
+/// stdin is null; stdout and stderr are left as the caller set them up.
+///
+/// Commands with a timeout run in their own process group, so that the
+/// whole group (including any sandbox processes) can be sent SIGTERM, and
+/// then SIGKILL if it hasn't exited after `KILL_GRACE_PERIOD`. That means
+/// they don't get the terminal's SIGINT, so build_exact kills them itself
+/// when it gets SIGINT or SIGTERM (see `forward_signals()`).
+pub fn run_to_completion(command: &mut Command, timeout: Option<Duration>) -> io::Result<Completed> {
+    command.stdin(Stdio::null());
+    let child = if timeout.is_some() {
+        FORWARD_SIGNALS.call_once(|| {
+            if let Err(e) = forward_signals() {
+                warn!("Commands with a timeout won't be killed if build_exact is interrupted: {:?}", e);
+            }
+        });
+        command.process_group(0);
+        // Hold the lock while spawning so that an interruption can't miss
+        // the new group.
+        let mut process_groups = PROCESS_GROUPS.lock().unwrap();
+        let child = command.spawn()?;
+        process_groups.push(child.id() as libc::pid_t);
+        child
+    } else {
+        command.spawn()?
+    };
+    let pid = child.id() as libc::pid_t;
+
+    let watchdog = timeout.map(|timeout| Watchdog::start(pid, timeout));
+    let waited = wait_with_usage(pid);
+    let timed_out = watchdog.map(Watchdog::stop).unwrap_or(false);
+    if timeout.is_some() {
+        PROCESS_GROUPS.lock().unwrap().retain(|group| *group != pid);
+    }
+    let (status, usage) = waited?;
+
+    Ok(Completed {
+        status,
+        usage,
+        timed_out,
+    })
+}
+
+/// Kills a process group if it is still running after its timeout.
+struct Watchdog {
+    process_group: libc::pid_t,
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(process_group: libc::pid_t, timeout: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            if !matches!(stopped.recv_timeout(timeout), Err(RecvTimeoutError::Timeout)) {
+                return false;
+            }
+            // It may have exited just before the deadline, but not been
+            // waited for yet.
+            if has_exited(process_group) {
+                return false;
+            }
+            kill_process_group(process_group, libc::SIGTERM);
+            if matches!(stopped.recv_timeout(KILL_GRACE_PERIOD), Err(RecvTimeoutError::Timeout)) {
+                kill_process_group(process_group, libc::SIGKILL);
+            }
+            true
+        });
+        Self {
+            process_group,
+            stop,
+            thread,
+        }
+    }
+
+    /// Call this once the process has exited. Returns whether it timed out,
+    /// in which case anything left in its group is killed straight away.
+    fn stop(self) -> bool {
+        drop(self.stop);
+        let timed_out = self.thread.join().expect("Watchdog panicked");
+        if timed_out {
+            kill_process_group(self.process_group, libc::SIGKILL);
+        }
+        timed_out
+    }
+}
+
+fn kill_process_group(process_group: libc::pid_t, signal: libc::c_int) {
+    // It fails with ESRCH if they have all exited already.
+    unsafe {
+        libc::kill(-process_group, signal);
+    }
+}
+
+/// Whether a child process has exited, without reaping it (which is left to
+/// `wait_with_usage()`). It has if it has already been reaped.
+fn has_exited(pid: libc::pid_t) -> bool {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    // It fails with ECHILD if it has already been reaped.
+    let result = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) };
+    result != 0 || unsafe { info.si_pid() } != 0
+}
+
+/// Make SIGINT and SIGTERM kill the process groups in `PROCESS_GROUPS` and
+/// then build_exact itself. The handler only writes the signal to a pipe,
+/// since hardly anything is async-signal-safe, and a thread does the rest.
+fn forward_signals() -> io::Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: `pipe2()` just opened it, and nothing else owns it.
+    let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+
+    thread::spawn(move || {
+        let mut signal = [0];
+        if signals.read_exact(&mut signal).is_err() {
+            return;
+        }
+        let signal = signal[0] as libc::c_int;
+        // Keep the lock so that no more commands are started. They are
+        // killed outright since build_exact won't be around to wait for
+        // them to exit.
+        let process_groups = PROCESS_GROUPS.lock().unwrap();
+        for process_group in process_groups.iter() {
+            kill_process_group(*process_group, libc::SIGKILL);
+        }
+        // Die from the signal as we would have without the handler.
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+        process::exit(128 + signal);
+    });
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // So that e.g. `wait4()` in other threads carries on.
+        action.sa_flags = libc::SA_RESTART;
+        unsafe {
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let signal = signal as u8;
+    unsafe {
+        libc::write(SIGNAL_PIPE.load(Ordering::SeqCst), &signal as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+/// Wait for a child process (which `Child::wait()` then mustn't be called
+/// for) and get its resource usage, which includes its own children that it
+/// waited for.
+fn wait_with_usage(pid: libc::pid_t) -> io::Result<(ExitStatus, ResourceUsage)> {
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+    while unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } < 0 {
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    let usage = ResourceUsage {
+        // Linux reports it in kilobytes.
+        peak_rss_bytes: rusage.ru_maxrss as u64 * 1024,
+        cpu_time: timeval_duration(&rusage.ru_utime) + timeval_duration(&rusage.ru_stime),
+    };
+    Ok((ExitStatus::from_raw(status), usage))
+}
+
+fn timeval_duration(time: &libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn kills_commands_that_time_out() {
+        let start = Instant::now();
+        let completed = run_to_completion(&mut shell("sleep 30"), Some(Duration::from_millis(100))).unwrap();
+        assert!(completed.timed_out);
+        assert_eq!(completed.status.signal(), Some(libc::SIGTERM));
+        assert!(start.elapsed() < KILL_GRACE_PERIOD);
+    }
+
+    #[test]
+    fn doesnt_time_out_commands_that_finish_in_time() {
+        let completed = run_to_completion(&mut shell("exit 3"), Some(Duration::from_secs(30))).unwrap();
+        assert!(!completed.timed_out);
+        assert_eq!(completed.status.code(), Some(3));
+    }
+
+    #[test]
+    fn doesnt_time_out_commands_that_exited_but_werent_waited_for() {
+        let mut child = shell("exit 0").process_group(0).spawn().unwrap();
+        let pid = child.id() as libc::pid_t;
+        while !has_exited(pid) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The deadline passes before it is reaped.
+        let watchdog = Watchdog::start(pid, Duration::from_millis(1));
+        thread::sleep(Duration::from_millis(50));
+        let status = child.wait().unwrap();
+        assert!(!watchdog.stop());
+        assert!(status.success());
+    }
+}
@@ -0,0 +1,408 @@
+use crate::environment::Scratch;
+use crate::namespace_sandbox::{self, Execroot};
+use crate::tracer::{self, TraceMode, TraceReport, Violation};
+use anyhow::{anyhow, bail, Context, Result};
+use landlock::{Access, AccessFs, BitFlags, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreated, RulesetCreatedAttr, ABI};
+use log::{debug, error};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// The Landlock ABI that we know about. Older kernels support a subset of it,
+/// which is handled on a best-effort basis.
+const LANDLOCK_ABI: ABI = ABI::V5;
+
+/// How commands are sandboxed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxKind {
+    /// Run commands directly.
+    None,
+    /// Run commands under the external `sandbox` binary from `sandbox/`.
+    External,
+    /// Restrict filesystem access with Landlock in the child before it execs.
+    Landlock,
+    /// Run commands in new user and mount namespaces with a private execroot.
+    Namespace,
+    /// Check every path syscall with seccomp and ptrace.
+    Seccomp,
+    /// Trace commands like `Seccomp`, but record the files they access
+    /// instead of blocking anything. This is used by `--learn-deps` and can't
+    /// be chosen with `--sandbox`.
+    Learn,
+}
+
+impl SandboxKind {
+    /// Whether the sandbox reports the accesses that it denies, as
+    /// `Violation`s. The others just make the command's syscalls fail.
+    pub fn reports_violations(self) -> bool {
+        matches!(self, SandboxKind::Seccomp | SandboxKind::Learn)
+    }
+}
+
+impl fmt::Display for SandboxKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SandboxKind::None => "none",
+            SandboxKind::External => "external",
+            SandboxKind::Landlock => "landlock",
+            SandboxKind::Namespace => "namespace",
+            SandboxKind::Seccomp => "seccomp",
+            SandboxKind::Learn => "learn",
+        })
+    }
+}
+
+impl FromStr for SandboxKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => SandboxKind::None,
+            "external" => SandboxKind::External,
+            "landlock" => SandboxKind::Landlock,
+            "namespace" => SandboxKind::Namespace,
+            "seccomp" => SandboxKind::Seccomp,
+            _ => bail!("Unknown sandbox: {} (expected none, external, landlock, namespace or seccomp)", s),
+        })
+    }
+}
+
+/// The files that a sandboxed command may access. Everything outside
+/// `sandboxed_dirs` is allowed; inside them only `read` can be read and only
+/// `write` can be written. Commands can only use the network if `network`
+/// is set; otherwise they get their own network namespace with just a
+/// loopback interface, whatever the kind of sandbox. `scratch` is the
+/// command's own scratch directory, which should be outside the sandboxed
+/// directories.
+pub struct SandboxAccess<'a> {
+    pub sandboxed_dirs: &'a [String],
+    pub read: &'a [String],
+    pub write: &'a [String],
+    pub network: bool,
+    pub scratch: &'a Scratch,
+}
+
+/// A command set up to run in a sandbox.
+pub struct SandboxedCommand {
+    pub command: Command,
+    /// Where the command sees `scratch.tmp()`, which is `/tmp` in the
+    /// namespace sandbox.
+    pub tmp_dir: PathBuf,
+    /// The namespace sandbox's execroot, which needs cleaning up afterwards.
+    execroot: Option<Execroot>,
+    /// Where the tracer writes its report.
+    trace_report: Option<PathBuf>,
+    /// Outputs that the Landlock sandbox created so that it could allow
+    /// writing them.
+    placeholders: Placeholders,
+}
+
+impl SandboxedCommand {
+    fn new(command: Command, access: &SandboxAccess) -> Self {
+        Self {
+            command,
+            tmp_dir: access.scratch.tmp(),
+            execroot: None,
+            trace_report: None,
+            placeholders: Placeholders::default(),
+        }
+    }
+
+    /// Clean up after the command has exited (successfully or not). Returns
+    /// the tracer's report if there is one.
+    pub fn finish(self) -> Result<Option<TraceReport>> {
+        // Before anything looks at the outputs.
+        drop(self.placeholders);
+        if let Some(execroot) = self.execroot {
+            execroot.finish()?;
+        }
+        self.trace_report.map(|path| tracer::read_report(&path)).transpose()
+    }
+}
+
+/// Which field of a command needs changing to allow an access that the
+/// sandbox denied: the path probably belongs in `inputs` or `outputs`, or
+/// the command needs `network`. Tests can't have outputs, so there's no
+/// suggestion for their writes.
+pub fn suggested_field(access: tracer::Access, is_test: bool) -> Option<&'static str> {
+    match access {
+        tracer::Access::Write if is_test => None,
+        tracer::Access::Write => Some("outputs"),
+        tracer::Access::Read | tracer::Access::Stat => Some("inputs"),
+        tracer::Access::Network => Some("network"),
+    }
+}
+
+/// Describe a sandbox violation, with a suggestion for how to fix it.
+pub fn describe_violation(violation: &Violation, is_test: bool) -> String {
+    let suggestion = match suggested_field(violation.access, is_test) {
+        Some("network") => "set `network: true` if the command needs it".to_string(),
+        Some(field) => format!("add it to `{}` if the command needs it", field),
+        None => "tests can't write to the sandboxed directories".to_string(),
+    };
+    format!(
+        "sandbox denied {} access to {:?} ({}); {}",
+        violation.access, violation.path, violation.syscall, suggestion
+    )
+}
+
+/// Create a `Command` that runs `command_line` in `working_dir` in the
+/// sandbox. The caller still needs to set the environment and so on, and
+/// call `finish()` afterwards. Per-command directories that the sandbox needs
+/// are created in `execroot_dir`.
+pub fn sandboxed_command(
+    kind: SandboxKind,
+    command_line: &[String],
+    working_dir: &Path,
+    access: &SandboxAccess,
+    execroot_dir: &Path,
+) -> Result<SandboxedCommand> {
+    if command_line.is_empty() {
+        bail!("Command is empty");
+    }
+
+    let mut sandboxed = match kind {
+        SandboxKind::None => {
+            let mut c = Command::new(&command_line[0]);
+            c.args(&command_line[1..]);
+            isolate_network_unless_allowed(&mut c, access)?;
+            SandboxedCommand::new(c, access)
+        }
+        SandboxKind::External => {
+            let mut sbc = Command::new(external_sandbox_binary());
+            sbc.arg("--sandbox");
+            sbc.args(access.sandboxed_dirs);
+            sbc.arg("--allow-read");
+            sbc.args(access.read);
+            if !access.write.is_empty() {
+                sbc.arg("--allow-write");
+                sbc.args(access.write);
+            }
+            sbc.arg("--");
+            sbc.args(command_line);
+
+            debug!("Sandboxed command: {:?}", sbc);
+
+            isolate_network_unless_allowed(&mut sbc, access)?;
+            SandboxedCommand::new(sbc, access)
+        }
+        SandboxKind::Landlock => {
+            let mut placeholders = Placeholders::default();
+            let ruleset = landlock_ruleset(access, &mut placeholders)?;
+            let mut c = Command::new(&command_line[0]);
+            c.args(&command_line[1..]);
+            isolate_network_unless_allowed(&mut c, access)?;
+            // Safety: `restrict_self` only makes async-signal-safe syscalls.
+            unsafe {
+                c.pre_exec(move || restrict_self(&ruleset));
+            }
+            SandboxedCommand {
+                placeholders,
+                ..SandboxedCommand::new(c, access)
+            }
+        }
+        SandboxKind::Namespace => {
+            let mut c = Command::new(&command_line[0]);
+            c.args(&command_line[1..]);
+            let execroot = namespace_sandbox::prepare(&mut c, working_dir, access, execroot_dir)?;
+            let tmp_dir = if execroot.has_private_tmp() { PathBuf::from("/tmp") } else { access.scratch.tmp() };
+            SandboxedCommand {
+                tmp_dir,
+                execroot: Some(execroot),
+                ..SandboxedCommand::new(c, access)
+            }
+        }
+        SandboxKind::Seccomp | SandboxKind::Learn => {
+            let mode = if kind == SandboxKind::Learn { TraceMode::Learn } else { TraceMode::Enforce };
+            let mut traced = tracer::traced_command(command_line, access, execroot_dir, mode)?;
+            isolate_network_unless_allowed(&mut traced.command, access)?;
+            SandboxedCommand {
+                trace_report: Some(traced.report),
+                ..SandboxedCommand::new(traced.command, access)
+            }
+        }
+    };
+
+    sandboxed.command.current_dir(working_dir);
+    Ok(sandboxed)
+}
+
+/// Check up front that commands can be sandboxed with `kind`, rather than
+/// failing every command.
+pub fn check_supported(kind: SandboxKind) -> Result<()> {
+    if kind == SandboxKind::Namespace {
+        namespace_sandbox::check_supported(libc::CLONE_NEWNS, "Use a different --sandbox.")?;
+    }
+    Ok(())
+}
+
+/// Whether commands can be given their own network namespace, which needs
+/// unprivileged user namespaces. If they can't the build still runs, but
+/// commands have network access, so this complains loudly.
+pub fn network_isolation_supported() -> bool {
+    match namespace_sandbox::check_supported(
+        libc::CLONE_NEWNET,
+        "Enable them so that commands can be run without network access.",
+    ) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{} Commands will be run with network access.", e);
+            false
+        }
+    }
+}
+
+fn isolate_network_unless_allowed(command: &mut Command, access: &SandboxAccess) -> Result<()> {
+    if access.network {
+        return Ok(());
+    }
+    namespace_sandbox::isolate_network(command)
+}
+
+/// Find the external `sandbox` binary in our own `PATH`, since the commands
+/// may be given a different one.
+fn external_sandbox_binary() -> PathBuf {
+    env::var_os("PATH")
+        .and_then(|paths| env::split_paths(&paths).map(|dir| dir.join("sandbox")).find(|path| path.is_file()))
+        .unwrap_or_else(|| PathBuf::from("sandbox"))
+}
+
+/// Build the Landlock ruleset for a command. This is done in the parent
+/// because it allocates and walks the filesystem, neither of which is safe
+/// to do between fork and exec. Returns an error if the kernel doesn't
+/// support Landlock, rather than silently running the command unsandboxed.
+fn landlock_ruleset(access: &SandboxAccess, placeholders: &mut Placeholders) -> Result<OwnedFd> {
+    let sandboxed_dirs: Vec<PathBuf> = access.sandboxed_dirs.iter().map(PathBuf::from).collect();
+
+    let ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(LANDLOCK_ABI))?
+        .create()?;
+
+    // Landlock only has allow rules, so allow everything that isn't in a
+    // sandboxed directory.
+    let mut ruleset = add_rules_outside(ruleset, Path::new("/"), &sandboxed_dirs)?;
+
+    // Inputs that don't exist (e.g. headers that a depfile listed last time
+    // but that have since been deleted) have nothing to allow.
+    for input in access.read {
+        let input = Path::new(input);
+        if input.exists() {
+            ruleset = add_rule(ruleset, input, AccessFs::from_read(LANDLOCK_ABI))?;
+        }
+    }
+
+    // Rules are attached to files rather than paths, and only to ones that
+    // exist, so outputs that don't exist yet are created empty in order to
+    // allow writing them. Rules apply to everything beneath the file they
+    // are attached to, so nothing is allowed on the directories that outputs
+    // go in; that would let commands create any file under them. That means
+    // commands can't replace an output with a new file (e.g. by deleting it
+    // or renaming one over it), and outputs that are directories must
+    // already exist.
+    for output in access.write {
+        let output = Path::new(output);
+        if !output.exists() {
+            placeholders.create(output)?;
+        }
+        ruleset = add_rule(ruleset, output, AccessFs::from_all(LANDLOCK_ABI))?;
+    }
+
+    let fd: Option<OwnedFd> = ruleset.into();
+    fd.ok_or_else(|| {
+        anyhow!("This kernel doesn't support Landlock (it needs Linux 5.13 or later with Landlock enabled). Use a different --sandbox.")
+    })
+}
+
+/// Allow full access to everything under `dir` except `sandboxed_dirs`.
+/// Directories that contain a sandboxed directory are recursed into; their
+/// other entries are allowed individually. That means new files can't be
+/// created directly in those directories, so e.g. a sandboxed directory
+/// directly in `/tmp` will stop commands creating temporary files anywhere
+/// but in their own `TMPDIR`.
+fn add_rules_outside(mut ruleset: RulesetCreated, dir: &Path, sandboxed_dirs: &[PathBuf]) -> Result<RulesetCreated> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Reading {:?}", dir))? {
+        let path = entry?.path();
+        if sandboxed_dirs.iter().any(|sandboxed| path.starts_with(sandboxed)) {
+            continue;
+        }
+        if sandboxed_dirs.iter().any(|sandboxed| sandboxed.starts_with(&path)) {
+            ruleset = add_rules_outside(ruleset, &path, sandboxed_dirs)?;
+            continue;
+        }
+        // Entries may be deleted after we list the directory, e.g. other
+        // commands' scratch directories in `/tmp`.
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Reading metadata for {:?}", path)),
+        };
+        // Symlinks would be followed, possibly into a sandboxed directory.
+        // Their targets are allowed (or not) in their own right.
+        if metadata.file_type().is_symlink() {
+            continue;
+        }
+        let fd = match PathFd::new(&path) {
+            Ok(fd) => fd,
+            Err(_) => continue,
+        };
+        ruleset = ruleset.add_rule(PathBeneath::new(fd, AccessFs::from_all(LANDLOCK_ABI)))?;
+    }
+    Ok(ruleset)
+}
+
+/// Empty files created for outputs that didn't exist, so that Landlock
+/// rules could be attached to them. The ones that the command didn't write
+/// are deleted when this is dropped, so that failed commands don't leave
+/// behind outputs that look up to date.
+#[derive(Default)]
+struct Placeholders {
+    files: Vec<(PathBuf, SystemTime)>,
+}
+
+impl Placeholders {
+    fn create(&mut self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Creating output {:?} for the sandbox", path))?;
+        let modified = file.metadata()?.modified()?;
+        self.files.push((path.to_owned(), modified));
+        Ok(())
+    }
+}
+
+impl Drop for Placeholders {
+    fn drop(&mut self) {
+        for (path, created) in self.files.iter() {
+            let untouched = fs::symlink_metadata(path)
+                .map(|metadata| metadata.is_file() && metadata.len() == 0 && metadata.modified().ok() == Some(*created))
+                .unwrap_or(false);
+            if untouched {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn add_rule(ruleset: RulesetCreated, path: &Path, access: BitFlags<AccessFs>) -> Result<RulesetCreated> {
+    let fd = PathFd::new(path).with_context(|| format!("Opening {:?} for the sandbox", path))?;
+    Ok(ruleset.add_rule(PathBeneath::new(fd, access))?)
+}
+
+/// Apply the ruleset to the current process. This runs in the child after
+/// fork, so it must only make raw syscalls.
+fn restrict_self(ruleset: &OwnedFd) -> io::Result<()> {
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use crate::buildinfo::BuildInfo;
+use crate::generator::{hash_read_files, Generator, GeneratorOutput};
+use anyhow::{anyhow, bail, Context, Result};
+use starlark::environment::{FrozenModule, Globals, Module};
+use starlark::eval::{Evaluator, FileLoader};
+use starlark::syntax::{AstModule, Dialect};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the global variable that the root script assigns the build
+/// description to. It has the same (camelCase) structure as the JSON that
+/// the Deno scripts output.
+const BUILD_INFO_VARIABLE: &str = "build";
+
+/// Name of the global variable that is set to the directory containing the
+/// script (with a trailing slash), like `thisDir` in the Deno scripts.
+const SCRIPT_DIR_VARIABLE: &str = "SCRIPT_DIR";
+
+/// Returns true if `file` should be evaluated with the Starlark frontend,
+/// i.e. it is a `.star` file or called `BUILD`.
+pub fn is_starlark_file(file: &Path) -> bool {
+    file.extension() == Some(OsStr::new("star")) || file.file_name() == Some(OsStr::new("BUILD"))
+}
+
+/// The built-in generator for Starlark files. Since it only reads the files
+/// that are `load()`ed we know exactly what it depends on.
+pub struct StarlarkGenerator {
+    file: PathBuf,
+}
+
+impl StarlarkGenerator {
+    pub fn new(file: &Path) -> Self {
+        Self {
+            file: file.to_owned(),
+        }
+    }
+}
+
+impl Generator for StarlarkGenerator {
+    fn hash_sources(&self, read_files: &[String]) -> Result<Option<String>> {
+        hash_read_files(read_files)
+    }
+
+    fn run(&self) -> Result<GeneratorOutput> {
+        let (build_info, loaded_files) = run_buildinfo(&self.file)?;
+        Ok(GeneratorOutput {
+            build_info,
+            read_files: loaded_files.iter().map(|file| file.to_string_lossy().into_owned()).collect(),
+        })
+    }
+}
+
+/// Evaluate a Starlark build file in-process and decode the BuildInfo. Also
+/// returns all the files that were evaluated.
+///
+/// Unlike Deno this is hermetic: Starlark has no filesystem or network access
+/// at all, except for the files that are explicitly `load()`ed (relative to
+/// the file that loads them). That means it is safe to re-run it at any time.
+pub fn run_buildinfo(file: &Path) -> Result<(BuildInfo, Vec<PathBuf>)> {
+    let globals = Globals::standard();
+    let context = EvalContext {
+        globals: &globals,
+        loaded_modules: RefCell::new(HashMap::new()),
+        loading: RefCell::new(Vec::new()),
+        evaluated_files: RefCell::new(Vec::new()),
+    };
+
+    let module = Module::new();
+    context.eval_file(file, &module)?;
+
+    let build = module
+        .get(BUILD_INFO_VARIABLE)
+        .ok_or_else(|| anyhow!("{:?} must assign the build description to `{}`", file, BUILD_INFO_VARIABLE))?;
+    let json = build
+        .to_json_value()
+        .with_context(|| format!("Converting `{}` to JSON", BUILD_INFO_VARIABLE))?;
+
+    let build_info: BuildInfo = serde_json::from_value(json)
+        .with_context(|| format!("Decoding `{}` from {:?}", BUILD_INFO_VARIABLE, file))?;
+
+    Ok((build_info, context.evaluated_files.into_inner()))
+}
+
+/// State shared by all the files evaluated for one build description.
+struct EvalContext<'a> {
+    globals: &'a Globals,
+    /// Files that have been `load()`ed, so that each is only evaluated once.
+    loaded_modules: RefCell<HashMap<PathBuf, FrozenModule>>,
+    /// Files that are currently being evaluated, to detect load cycles.
+    loading: RefCell<Vec<PathBuf>>,
+    /// All the files that have been evaluated.
+    evaluated_files: RefCell<Vec<PathBuf>>,
+}
+
+impl<'a> EvalContext<'a> {
+    /// Parse and evaluate a file into `module`.
+    fn eval_file(&self, file: &Path, module: &Module) -> Result<()> {
+        let file = fs::canonicalize(file).with_context(|| format!("Resolving {:?}", file))?;
+        if self.loading.borrow().contains(&file) {
+            bail!("{:?} is loaded recursively", file);
+        }
+
+        let content = fs::read_to_string(&file).with_context(|| format!("Reading {:?}", file))?;
+        self.evaluated_files.borrow_mut().push(file.clone());
+        let ast = AstModule::parse(&file.to_string_lossy(), content, &Dialect::Extended)
+            .map_err(starlark::Error::into_anyhow)?;
+
+        let dir = file.parent().expect("Canonical file path has no parent");
+        module.set(SCRIPT_DIR_VARIABLE, module.heap().alloc(format!("{}/", dir.to_string_lossy())));
+
+        let loader = RelativeFileLoader {
+            dir: dir.to_owned(),
+            context: self,
+        };
+
+        self.loading.borrow_mut().push(file.clone());
+        let result = {
+            let mut eval = Evaluator::new(module);
+            eval.set_loader(&loader);
+            eval.eval_module(ast, self.globals).map(|_| ())
+        };
+        self.loading.borrow_mut().pop();
+        result.map_err(starlark::Error::into_anyhow)
+    }
+
+    /// Evaluate a `load()`ed file, or return it if it has already been loaded.
+    fn load_file(&self, file: &Path) -> Result<FrozenModule> {
+        let file = fs::canonicalize(file).with_context(|| format!("Resolving {:?}", file))?;
+        if let Some(module) = self.loaded_modules.borrow().get(&file) {
+            return Ok(module.clone());
+        }
+
+        let module = Module::new();
+        self.eval_file(&file, &module)?;
+        let module = module.freeze()?;
+        self.loaded_modules.borrow_mut().insert(file, module.clone());
+        Ok(module)
+    }
+}
+
+/// Load `load()`ed files relative to the directory of the file that loads
+/// them.
+struct RelativeFileLoader<'a> {
+    dir: PathBuf,
+    context: &'a EvalContext<'a>,
+}
+
+impl<'a> FileLoader for RelativeFileLoader<'a> {
+    fn load(&self, path: &str) -> starlark::Result<FrozenModule> {
+        if Path::new(path).is_absolute() {
+            return Err(anyhow!("load() paths must be relative: {:?}", path).into());
+        }
+        self.context
+            .load_file(&self.dir.join(path))
+            .map_err(starlark::Error::from)
+    }
+}
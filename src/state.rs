@@ -0,0 +1,356 @@
+use crate::buildinfo::{BuildCommand, BuildInfo};
+use crate::fingerprint::Fingerprints;
+use anyhow::{bail, Context, Result};
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Name of the database inside the state directory.
+const DATABASE_FILE: &str = "state.sqlite";
+
+/// Schema migrations. Entry `n` upgrades the database from version `n` to
+/// version `n + 1`, so the current version is `MIGRATIONS.len()`. Never edit
+/// an existing entry; add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // Version 1.
+    "
+    CREATE TABLE commands (
+        key TEXT PRIMARY KEY NOT NULL,
+        command_line TEXT NOT NULL,
+        env TEXT NOT NULL,
+        fingerprints TEXT,
+        duration_ms INTEGER NOT NULL,
+        exit_status INTEGER,
+        buildinfo_hash TEXT
+    );
+    CREATE TABLE buildinfo (
+        config TEXT PRIMARY KEY NOT NULL,
+        hash TEXT NOT NULL
+    );
+    ",
+    // Version 2: cache the generated build description.
+    "
+    ALTER TABLE buildinfo ADD COLUMN json TEXT;
+    ",
+    // Version 3: files that the generator read (JSON list).
+    "
+    ALTER TABLE buildinfo ADD COLUMN read_files TEXT;
+    ",
+    // Version 4: inputs discovered from depfiles (JSON list).
+    "
+    ALTER TABLE commands ADD COLUMN discovered_inputs TEXT;
+    ",
+    // Version 5: resource usage.
+    "
+    ALTER TABLE commands ADD COLUMN peak_rss_bytes INTEGER;
+    ALTER TABLE commands ADD COLUMN cpu_time_ms INTEGER;
+    ",
+];
+
+/// Information that is kept between builds, in an SQLite database in the
+/// `.build_exact` directory. Everything is loaded up front and changes are
+/// written back by `save()`.
+pub struct BuildState {
+    connection: Connection,
+    /// What happened the last time each command was run, keyed by
+    /// `build_command_key()` or `test_command_key()`.
+    commands: HashMap<String, CommandRecord>,
+    /// Keys of the commands that have changed since they were loaded.
+    dirty_commands: HashSet<String>,
+    /// Hash of the build description that is being built, if known. It is
+    /// recorded with each command that is run.
+    pub buildinfo_hash: Option<String>,
+}
+
+/// What happened the last time a command was run.
+#[derive(Clone, Default)]
+pub struct CommandRecord {
+    /// The command line that was run.
+    pub command_line: Vec<String>,
+    /// The environment variables it was given (see `command_env()`). With
+    /// `--inherit-env` these were added to the ambient environment.
+    pub env: HashMap<String, String>,
+    /// How long the command took to run, in milliseconds.
+    pub duration_ms: u64,
+    /// Its exit code, or `None` if it was killed by a signal.
+    pub exit_status: Option<i32>,
+    /// The most memory it used (see `ResourceUsage`), if known.
+    pub peak_rss_bytes: Option<u64>,
+    /// How much CPU time it used, in milliseconds, if known.
+    pub cpu_time_ms: Option<u64>,
+    /// Content hashes from the last successful run, when building in content
+    /// hash mode.
+    pub fingerprints: Option<Fingerprints>,
+    /// Inputs that were listed in the command's depfile.
+    pub discovered_inputs: Vec<String>,
+    /// Hash of the build description it came from.
+    pub buildinfo_hash: Option<String>,
+}
+
+impl BuildState {
+    /// Open the state database in `state_dir`, creating it (and the
+    /// directory) if this is the first build, and upgrading it if it was
+    /// written by an older version.
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(state_dir).with_context(|| format!("Creating {:?}", state_dir))?;
+
+        let path = state_dir.join(DATABASE_FILE);
+        let mut connection = Connection::open(&path).with_context(|| format!("Opening {:?}", path))?;
+        migrate(&mut connection).with_context(|| format!("Upgrading {:?}", path))?;
+
+        let mut state = Self {
+            connection,
+            commands: HashMap::new(),
+            dirty_commands: HashSet::new(),
+            buildinfo_hash: None,
+        };
+        state.load_commands()?;
+        Ok(state)
+    }
+
+    fn load_commands(&mut self) -> Result<()> {
+        let mut statement = self.connection.prepare(
+            "SELECT key, command_line, env, fingerprints, duration_ms, exit_status, buildinfo_hash, discovered_inputs,
+                peak_rss_bytes, cpu_time_ms
+                FROM commands",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: String = row.get(0)?;
+            let command_line: String = row.get(1)?;
+            let env: String = row.get(2)?;
+            let fingerprints: Option<String> = row.get(3)?;
+            let duration_ms: i64 = row.get(4)?;
+            let discovered_inputs: Option<String> = row.get(7)?;
+            let peak_rss_bytes: Option<i64> = row.get(8)?;
+            let cpu_time_ms: Option<i64> = row.get(9)?;
+
+            let record = CommandRecord {
+                command_line: serde_json::from_str(&command_line)?,
+                env: serde_json::from_str(&env)?,
+                duration_ms: duration_ms as u64,
+                exit_status: row.get(5)?,
+                peak_rss_bytes: peak_rss_bytes.map(|bytes| bytes as u64),
+                cpu_time_ms: cpu_time_ms.map(|ms| ms as u64),
+                fingerprints: fingerprints.map(|f| serde_json::from_str(&f)).transpose()?,
+                buildinfo_hash: row.get(6)?,
+                discovered_inputs: discovered_inputs.map(|d| serde_json::from_str(&d)).transpose()?.unwrap_or_default(),
+            };
+            self.commands.insert(key, record);
+        }
+        Ok(())
+    }
+
+    /// Get the record for a command.
+    pub fn command(&self, key: &str) -> Option<&CommandRecord> {
+        self.commands.get(key)
+    }
+
+    /// Replace the record for a command. It is written to the database by
+    /// `save()`.
+    pub fn set_command(&mut self, key: String, record: CommandRecord) {
+        self.dirty_commands.insert(key.clone());
+        self.commands.insert(key, record);
+    }
+
+    /// Get the build description that was generated from `config` last
+    /// time, if its sources still have the same `hash`.
+    pub fn cached_buildinfo(&self, config: &Path, hash: &str) -> Result<Option<BuildInfo>> {
+        let json: Option<Option<String>> = self.connection
+            .query_row(
+                "SELECT json FROM buildinfo WHERE config = ? AND hash = ?",
+                params![config.to_string_lossy(), hash],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match json.flatten() {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the files that the generator reported reading the last time it
+    /// was run for `config`.
+    pub fn buildinfo_read_files(&self, config: &Path) -> Result<Vec<String>> {
+        let read_files: Option<Option<String>> = self.connection
+            .query_row(
+                "SELECT read_files FROM buildinfo WHERE config = ?",
+                params![config.to_string_lossy()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match read_files.flatten() {
+            Some(read_files) => Ok(serde_json::from_str(&read_files)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Record the build description generated from `config`, the hash of
+    /// its sources, and the files the generator read.
+    pub fn save_buildinfo(&self, config: &Path, hash: &str, build_info: &BuildInfo, read_files: &[String]) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO buildinfo (config, hash, json, read_files) VALUES (?, ?, ?, ?)",
+            params![
+                config.to_string_lossy(),
+                hash,
+                serde_json::to_string(build_info)?,
+                serde_json::to_string(read_files)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Write all changed command records to the database.
+    pub fn save(&mut self) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare(
+                "INSERT OR REPLACE INTO commands
+                    (key, command_line, env, fingerprints, duration_ms, exit_status, buildinfo_hash, discovered_inputs,
+                        peak_rss_bytes, cpu_time_ms)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for key in self.dirty_commands.iter() {
+                let record = &self.commands[key];
+                let fingerprints = record.fingerprints.as_ref().map(serde_json::to_string).transpose()?;
+                statement.execute(params![
+                    key,
+                    serde_json::to_string(&record.command_line)?,
+                    serde_json::to_string(&record.env)?,
+                    fingerprints,
+                    record.duration_ms as i64,
+                    record.exit_status,
+                    record.buildinfo_hash,
+                    serde_json::to_string(&record.discovered_inputs)?,
+                    record.peak_rss_bytes.map(|bytes| bytes as i64),
+                    record.cpu_time_ms.map(|ms| ms as i64),
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        self.dirty_commands.clear();
+        Ok(())
+    }
+}
+
+/// Bring the database schema up to date. The schema version is stored in
+/// SQLite's `user_version`, which is 0 for a new database.
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "Build state has version {} but this version of build_exact only supports up to {}. Delete it to start again.",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Upgrading build state from version {} to {}", from_version, from_version + 1);
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", from_version + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+/// The key used to identify a build command between builds. Output files can
+/// only be generated by one command so they identify it even if the command
+/// line changes. Commands without outputs fall back to the command line.
+/// This must be called with the command as it is declared, before any dyndep
+/// file adds outputs to it.
+pub fn build_command_key(command: &BuildCommand) -> String {
+    if command.outputs.is_empty() {
+        format!("command:{}", command.command.join(" "))
+    } else {
+        format!("outputs:{}", command.outputs.join(":"))
+    }
+}
+
+/// The key used to identify a test between builds.
+pub fn test_command_key(test_name: &str) -> String {
+    format!("test:{}", test_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn version(state: &BuildState) -> usize {
+        state.connection.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn creates_new_databases_at_the_latest_version() {
+        let dir = TempDir::new().unwrap();
+        let state = BuildState::load(&dir.path().join("state")).unwrap();
+        assert_eq!(version(&state), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn upgrades_version_1_databases() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        {
+            let connection = Connection::open(dir.join(DATABASE_FILE)).unwrap();
+            connection.execute_batch(MIGRATIONS[0]).unwrap();
+            connection.pragma_update(None, "user_version", 1).unwrap();
+            connection
+                .execute(
+                    "INSERT INTO commands (key, command_line, env, duration_ms, exit_status) VALUES (?, ?, ?, ?, ?)",
+                    params!["outputs:/out/a.o", r#"["cc","a.c"]"#, r#"{"LANG":"C"}"#, 1500, 0],
+                )
+                .unwrap();
+            connection
+                .execute("INSERT INTO buildinfo (config, hash) VALUES (?, ?)", params!["/src/build.star", "abc"])
+                .unwrap();
+        }
+
+        let mut state = BuildState::load(dir).unwrap();
+        assert_eq!(version(&state), MIGRATIONS.len());
+        let record = state.command("outputs:/out/a.o").unwrap();
+        assert_eq!(record.command_line, vec!["cc", "a.c"]);
+        assert_eq!(record.env["LANG"], "C");
+        assert_eq!(record.duration_ms, 1500);
+        assert_eq!(record.exit_status, Some(0));
+        assert!(record.discovered_inputs.is_empty());
+        assert_eq!(record.peak_rss_bytes, None);
+        // Version 1 didn't store the build description itself.
+        assert!(state.cached_buildinfo(Path::new("/src/build.star"), "abc").unwrap().is_none());
+        assert!(state.buildinfo_read_files(Path::new("/src/build.star")).unwrap().is_empty());
+
+        // The new columns can be written and read back.
+        state.set_command(
+            "outputs:/out/a.o".to_string(),
+            CommandRecord {
+                discovered_inputs: vec!["/src/a.h".to_string()],
+                peak_rss_bytes: Some(1 << 20),
+                cpu_time_ms: Some(1200),
+                ..record.clone()
+            },
+        );
+        state.save().unwrap();
+        drop(state);
+
+        let state = BuildState::load(dir).unwrap();
+        let record = state.command("outputs:/out/a.o").unwrap();
+        assert_eq!(record.discovered_inputs, vec!["/src/a.h"]);
+        assert_eq!(record.peak_rss_bytes, Some(1 << 20));
+        assert_eq!(record.cpu_time_ms, Some(1200));
+    }
+
+    #[test]
+    fn refuses_databases_from_newer_versions() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        let connection = Connection::open(dir.join(DATABASE_FILE)).unwrap();
+        connection.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        drop(connection);
+
+        assert!(BuildState::load(dir).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use serde::Serialize;
+use std::time::Duration;
+
+/// What happened to the commands in a build, keyed like the build state,
+/// and to the tests. It is printed at the end of the build.
+#[derive(Default)]
+pub struct BuildSummary {
+    /// Build commands that ran successfully.
+    pub succeeded: Vec<String>,
+    /// Build commands that were already up to date.
+    pub up_to_date: Vec<String>,
+    /// Build commands that failed or couldn't be run.
+    pub failed: Vec<String>,
+    /// Build commands that were killed because they ran for longer than
+    /// their timeout, and the timeout.
+    pub timed_out: Vec<(String, Duration)>,
+    /// Build commands that weren't run because something they depend on
+    /// failed, or because too many commands failed.
+    pub skipped: Vec<String>,
+    /// The tests that were requested.
+    pub tests: Vec<TestResult>,
+}
+
+/// What happened to a test.
+pub struct TestResult {
+    pub name: String,
+    pub status: TestStatus,
+    /// How long it ran for, if it ran.
+    pub duration: Option<Duration>,
+    /// Its exit code, if it ran (or its result was reused) and it wasn't
+    /// killed by a signal.
+    pub exit_code: Option<i32>,
+    /// Whether it passed last time, and that result was reused because
+    /// nothing it depends on has changed.
+    pub cached: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    TimedOut,
+    /// It wasn't run because a build command failed.
+    Skipped,
+}
+
+impl TestStatus {
+    fn label(self) -> &'static str {
+        match self {
+            TestStatus::Passed => "PASSED",
+            TestStatus::Failed => "FAILED",
+            TestStatus::TimedOut => "TIMED OUT",
+            TestStatus::Skipped => "SKIPPED",
+        }
+    }
+}
+
+impl BuildSummary {
+    /// Whether every build command that was needed succeeded, and every test
+    /// passed.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.timed_out.is_empty() && self.tests.iter().all(|test| test.status == TestStatus::Passed)
+    }
+
+    /// Print the summary to stderr, listing the failed and skipped commands
+    /// and all the tests, with a command to rerun each failed one. `rerun` is
+    /// the build_exact command line to do that with, without the targets.
+    pub fn print(&self, rerun: &str) {
+        eprintln!(
+            "{} succeeded, {} up to date, {} failed, {} timed out, {} skipped",
+            self.succeeded.len(),
+            self.up_to_date.len(),
+            self.failed.len(),
+            self.timed_out.len(),
+            self.skipped.len()
+        );
+        print_list("Failed", &self.failed);
+        let timed_out: Vec<String> = self
+            .timed_out
+            .iter()
+            .map(|(key, timeout)| format!("{} (timeout {:?})", key, timeout))
+            .collect();
+        print_list("Timed out", &timed_out);
+        print_list("Skipped", &self.skipped);
+
+        if self.tests.is_empty() {
+            return;
+        }
+        let count = |status| self.tests.iter().filter(|test| test.status == status).count();
+        let cached = match self.tests.iter().filter(|test| test.cached).count() {
+            0 => String::new(),
+            cached => format!(" ({} cached)", cached),
+        };
+        eprintln!(
+            "Tests: {} passed{}, {} failed, {} timed out, {} skipped",
+            count(TestStatus::Passed),
+            cached,
+            count(TestStatus::Failed),
+            count(TestStatus::TimedOut),
+            count(TestStatus::Skipped)
+        );
+
+        let mut tests: Vec<&TestResult> = self.tests.iter().collect();
+        tests.sort_by(|a, b| a.name.cmp(&b.name));
+        for test in tests.iter() {
+            match test.duration {
+                Some(duration) => eprintln!("  {:<9} {} ({:.1}s)", test.status.label(), test.name, duration.as_secs_f64()),
+                None if test.cached => eprintln!("  {:<9} {} (cached)", test.status.label(), test.name),
+                None => eprintln!("  {:<9} {}", test.status.label(), test.name),
+            }
+        }
+
+        let failed: Vec<&&TestResult> = tests
+            .iter()
+            .filter(|test| matches!(test.status, TestStatus::Failed | TestStatus::TimedOut))
+            .collect();
+        if !failed.is_empty() {
+            eprintln!("To rerun the failed tests:");
+            for test in failed {
+                eprintln!("  {} {}", rerun, shell_quote(&format!("test:{}", test.name)));
+            }
+        }
+    }
+}
+
+/// Quote `arg` for a POSIX shell, if it needs it.
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn print_list(heading: &str, keys: &[String]) {
+    if keys.is_empty() {
+        return;
+    }
+    let mut keys = keys.to_vec();
+    keys.sort();
+    eprintln!("{}:", heading);
+    for key in keys {
+        eprintln!("  {}", key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_only_quotes_when_needed() {
+        assert_eq!(shell_quote("test:foo/bar.cpp"), "test:foo/bar.cpp");
+        assert_eq!(shell_quote("my build.json"), "'my build.json'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
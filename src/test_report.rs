@@ -0,0 +1,471 @@
+use crate::logs::{read_junit, read_log};
+use crate::state::test_command_key;
+use crate::summary::{TestResult, TestStatus};
+use anyhow::{Context, Result};
+use log::warn;
+use quick_xml::events::{BytesDecl, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// The name of the suite that has a test case for each test.
+const SUITE_NAME: &str = "build_exact";
+
+/// The results of the tests in a build, written with `--test-report` for
+/// CI systems. It is JUnit XML, or JSON with the same information if the
+/// file name ends in `.json`.
+#[derive(Serialize)]
+pub struct TestReport {
+    tests: Vec<ReportedTest>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportedTest {
+    name: String,
+    status: TestStatus,
+    /// How long it ran for in seconds, if it ran.
+    time: Option<f64>,
+    exit_code: Option<i32>,
+    timed_out: bool,
+    /// Whether its result from an earlier build was reused.
+    cached: bool,
+    /// Its stdout and stderr.
+    output: String,
+    /// The test cases from the JUnit XML that it wrote to `XML_OUTPUT_FILE`.
+    cases: Vec<TestCase>,
+}
+
+/// A test case from a test's own JUnit XML.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TestCase {
+    name: String,
+    classname: Option<String>,
+    time: Option<f64>,
+    status: CaseStatus,
+    message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum CaseStatus {
+    Passed,
+    Failed,
+    Error,
+    Skipped,
+}
+
+impl TestReport {
+    /// Collect the results of `tests`, with their output from `log_dir`.
+    pub fn new(tests: &[TestResult], log_dir: &Path) -> Result<Self> {
+        let mut reported = Vec::with_capacity(tests.len());
+        for test in tests {
+            // Tests that didn't run may have a log from an earlier build,
+            // which is only relevant if their result was reused.
+            let key = test_command_key(&test.name);
+            let ran = test.duration.is_some() || test.cached;
+            let (output, junit) = if ran {
+                (read_log(log_dir, &key)?.unwrap_or_default(), read_junit(log_dir, &key)?)
+            } else {
+                (Vec::new(), None)
+            };
+            let cases = match &junit {
+                Some(junit) => parse_junit(junit).unwrap_or_else(|e| {
+                    warn!("Ignoring the JUnit XML written by test {}: {:?}", test.name, e);
+                    Vec::new()
+                }),
+                None => Vec::new(),
+            };
+            reported.push(ReportedTest {
+                name: test.name.clone(),
+                status: test.status,
+                time: test.duration.map(|duration| duration.as_secs_f64()),
+                exit_code: test.exit_code,
+                timed_out: test.status == TestStatus::TimedOut,
+                cached: test.cached,
+                output: String::from_utf8_lossy(&output).into_owned(),
+                cases,
+            });
+        }
+        reported.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self { tests: reported })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let contents = if path.extension() == Some(OsStr::new("json")) {
+            serde_json::to_vec_pretty(self)?
+        } else {
+            self.to_junit()?
+        };
+        fs::write(path, contents).with_context(|| format!("Writing {:?}", path))?;
+        Ok(())
+    }
+
+    /// One suite with a test case for each test, then a suite for each test
+    /// that wrote its own JUnit XML, named after the test.
+    fn to_junit(&self) -> Result<Vec<u8>> {
+        let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+        writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+
+        let statuses: Vec<CaseStatus> = self
+            .tests
+            .iter()
+            .map(|test| test.case_status())
+            .chain(self.tests.iter().flat_map(|test| test.cases.iter().map(|case| case.status)))
+            .collect();
+        let total_time = total(self.tests.iter().filter_map(|test| test.time));
+        writer
+            .create_element("testsuites")
+            .with_attributes(as_strs(&suite_attributes(SUITE_NAME, &statuses, total_time)))
+            .write_inner_content(|writer| -> Result<()> {
+                let statuses: Vec<CaseStatus> = self.tests.iter().map(|test| test.case_status()).collect();
+                writer
+                    .create_element("testsuite")
+                    .with_attributes(as_strs(&suite_attributes(SUITE_NAME, &statuses, total_time)))
+                    .write_inner_content(|writer| -> Result<()> {
+                        for test in self.tests.iter() {
+                            test.write_junit(writer)?;
+                        }
+                        Ok(())
+                    })?;
+
+                for test in self.tests.iter().filter(|test| !test.cases.is_empty()) {
+                    let statuses: Vec<CaseStatus> = test.cases.iter().map(|case| case.status).collect();
+                    let time = total(test.cases.iter().filter_map(|case| case.time));
+                    writer
+                        .create_element("testsuite")
+                        .with_attributes(as_strs(&suite_attributes(&test.name, &statuses, time)))
+                        .write_inner_content(|writer| -> Result<()> {
+                            for case in test.cases.iter() {
+                                case.write_junit(writer)?;
+                            }
+                            Ok(())
+                        })?;
+                }
+                Ok(())
+            })?;
+
+        let mut xml = writer.into_inner();
+        xml.write_all(b"\n")?;
+        Ok(xml)
+    }
+}
+
+impl ReportedTest {
+    fn case_status(&self) -> CaseStatus {
+        match self.status {
+            TestStatus::Passed => CaseStatus::Passed,
+            TestStatus::Failed | TestStatus::TimedOut => CaseStatus::Failed,
+            TestStatus::Skipped => CaseStatus::Skipped,
+        }
+    }
+
+    fn write_junit(&self, writer: &mut Writer<Vec<u8>>) -> Result<()> {
+        let mut attributes = vec![("name", self.name.clone()), ("classname", SUITE_NAME.to_owned())];
+        if let Some(time) = self.time {
+            attributes.push(("time", format!("{:.3}", time)));
+        }
+        writer
+            .create_element("testcase")
+            .with_attributes(as_strs(&attributes))
+            .write_inner_content(|writer| -> Result<()> {
+                writer.create_element("properties").write_inner_content(|writer| -> Result<()> {
+                    if let Some(code) = self.exit_code {
+                        writer
+                            .create_element("property")
+                            .with_attributes([("name", "exitCode"), ("value", code.to_string().as_str())])
+                            .write_empty()?;
+                    }
+                    writer
+                        .create_element("property")
+                        .with_attributes([("name", "timedOut"), ("value", if self.timed_out { "true" } else { "false" })])
+                        .write_empty()?;
+                    writer
+                        .create_element("property")
+                        .with_attributes([("name", "cached"), ("value", if self.cached { "true" } else { "false" })])
+                        .write_empty()?;
+                    Ok(())
+                })?;
+
+                match self.status {
+                    TestStatus::Passed => {}
+                    TestStatus::Failed => {
+                        let message = match self.exit_code {
+                            Some(code) => format!("Exited with code {}", code),
+                            None if self.time.is_some() => "Killed by a signal".to_owned(),
+                            None => "Couldn't be run".to_owned(),
+                        };
+                        writer.create_element("failure").with_attribute(("message", message.as_str())).write_empty()?;
+                    }
+                    TestStatus::TimedOut => {
+                        writer
+                            .create_element("failure")
+                            .with_attributes([("message", "Timed out"), ("type", "timeout")])
+                            .write_empty()?;
+                    }
+                    TestStatus::Skipped => {
+                        writer
+                            .create_element("skipped")
+                            .with_attribute(("message", "A build command that it depends on failed"))
+                            .write_empty()?;
+                    }
+                }
+
+                if !self.output.is_empty() {
+                    writer
+                        .create_element("system-out")
+                        .write_text_content(BytesText::new(&xml_safe(&self.output)))?;
+                }
+                Ok(())
+            })?;
+        Ok(())
+    }
+}
+
+impl TestCase {
+    fn write_junit(&self, writer: &mut Writer<Vec<u8>>) -> Result<()> {
+        let mut attributes = vec![("name", self.name.clone())];
+        if let Some(classname) = &self.classname {
+            attributes.push(("classname", classname.clone()));
+        }
+        if let Some(time) = self.time {
+            attributes.push(("time", format!("{:.3}", time)));
+        }
+        let element = writer
+            .create_element("testcase")
+            .with_attributes(as_strs(&attributes));
+
+        let result = match self.status {
+            CaseStatus::Passed => None,
+            CaseStatus::Failed => Some("failure"),
+            CaseStatus::Error => Some("error"),
+            CaseStatus::Skipped => Some("skipped"),
+        };
+        match result {
+            Some(result) => {
+                element.write_inner_content(|writer| -> Result<()> {
+                    let result = writer.create_element(result);
+                    match &self.message {
+                        Some(message) => result.with_attribute(("message", message.as_str())).write_empty()?,
+                        None => result.write_empty()?,
+                    };
+                    Ok(())
+                })?;
+            }
+            None => {
+                element.write_empty()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The attributes of a `<testsuite>` or `<testsuites>` element.
+fn suite_attributes(name: &str, statuses: &[CaseStatus], time: f64) -> Vec<(&'static str, String)> {
+    let count = |status| statuses.iter().filter(|s| **s == status).count().to_string();
+    vec![
+        ("name", name.to_owned()),
+        ("tests", statuses.len().to_string()),
+        ("failures", count(CaseStatus::Failed)),
+        ("errors", count(CaseStatus::Error)),
+        ("skipped", count(CaseStatus::Skipped)),
+        ("time", format!("{:.3}", time)),
+    ]
+}
+
+/// The sum of some times. Unlike `Iterator::sum()` this is 0, not -0, if
+/// there aren't any.
+fn total(times: impl Iterator<Item = f64>) -> f64 {
+    times.fold(0.0, |total, time| total + time)
+}
+
+/// Attributes in the form that quick-xml takes them.
+fn as_strs<'a>(attributes: &'a [(&'a str, String)]) -> impl Iterator<Item = (&'a str, &'a str)> {
+    attributes.iter().map(|(name, value)| (*name, value.as_str()))
+}
+
+/// Read the test cases from JUnit XML, from any `<testsuite>`s (nested or
+/// not). Everything apart from their results is ignored.
+fn parse_junit(xml: &str) -> Result<Vec<TestCase>> {
+    let mut reader = Reader::from_str(xml);
+    let mut cases = Vec::new();
+    let mut current: Option<TestCase> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) if element.name().as_ref() == b"testcase" => {
+                current = Some(parse_test_case(&element)?);
+            }
+            Event::Empty(element) if element.name().as_ref() == b"testcase" => {
+                cases.push(parse_test_case(&element)?);
+            }
+            Event::End(element) if element.name().as_ref() == b"testcase" => {
+                cases.extend(current.take());
+            }
+            Event::Start(element) | Event::Empty(element) => {
+                let status = match element.name().as_ref() {
+                    b"failure" => CaseStatus::Failed,
+                    b"error" => CaseStatus::Error,
+                    b"skipped" => CaseStatus::Skipped,
+                    _ => continue,
+                };
+                if let Some(case) = current.as_mut() {
+                    case.status = status;
+                    case.message = attribute(&element, b"message")?;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(cases)
+}
+
+fn parse_test_case(element: &BytesStart) -> Result<TestCase> {
+    Ok(TestCase {
+        name: attribute(element, b"name")?.unwrap_or_default(),
+        classname: attribute(element, b"classname")?,
+        time: attribute(element, b"time")?.and_then(|time| time.parse().ok()),
+        status: CaseStatus::Passed,
+        message: None,
+    })
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        if attribute.key.as_ref() == name {
+            return Ok(Some(attribute.unescape_value()?.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Replace the characters that aren't allowed in XML 1.0 (even escaped),
+/// like most control characters, which commands' output can contain.
+fn xml_safe(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\t' | '\n' | '\r' => c,
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => '\u{fffd}',
+            _ => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::{keep_junit, log_path};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn result(name: &str, status: TestStatus, exit_code: Option<i32>) -> TestResult {
+        TestResult {
+            name: name.to_owned(),
+            status,
+            duration: Some(Duration::from_millis(1500)),
+            exit_code,
+            cached: false,
+        }
+    }
+
+    /// The name, classname, status and message of each case.
+    fn summarise(cases: &[TestCase]) -> Vec<(&str, Option<&str>, CaseStatus, Option<&str>)> {
+        cases
+            .iter()
+            .map(|case| (case.name.as_str(), case.classname.as_deref(), case.status, case.message.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn junit_round_trips() {
+        let log_dir = TempDir::new().unwrap();
+        let log_dir = log_dir.path();
+        let key = test_command_key("unit");
+        fs::write(log_path(log_dir, &key), "ran \u{1b}[1mbold\u{1b}[0m & <fine>\n").unwrap();
+        let written = log_dir.join("written.xml");
+        fs::write(
+            &written,
+            r#"<testsuites><testsuite name="inner">
+                 <testcase name="adds" classname="maths" time="0.25"/>
+                 <testcase name="divides" classname="maths"><failure message="divided by zero"/></testcase>
+               </testsuite></testsuites>"#,
+        )
+        .unwrap();
+        keep_junit(&written, log_dir, &key).unwrap();
+
+        let tests = vec![
+            result("unit", TestStatus::Failed, Some(1)),
+            result("slow", TestStatus::TimedOut, None),
+            TestResult {
+                name: "blocked".to_owned(),
+                status: TestStatus::Skipped,
+                duration: None,
+                exit_code: None,
+                cached: false,
+            },
+        ];
+        let report = TestReport::new(&tests, log_dir).unwrap();
+        let xml = String::from_utf8(report.to_junit().unwrap()).unwrap();
+
+        let cases = parse_junit(&xml).unwrap();
+        assert_eq!(
+            summarise(&cases),
+            vec![
+                ("blocked", Some(SUITE_NAME), CaseStatus::Skipped, Some("A build command that it depends on failed")),
+                ("slow", Some(SUITE_NAME), CaseStatus::Failed, Some("Timed out")),
+                ("unit", Some(SUITE_NAME), CaseStatus::Failed, Some("Exited with code 1")),
+                ("adds", Some("maths"), CaseStatus::Passed, None),
+                ("divides", Some("maths"), CaseStatus::Failed, Some("divided by zero")),
+            ]
+        );
+        assert_eq!(cases[0].time, None);
+        assert_eq!(cases[1].time, Some(1.5));
+        assert_eq!(cases[3].time, Some(0.25));
+        assert!(xml.contains("ran \u{fffd}[1mbold\u{fffd}[0m &amp; &lt;fine&gt;"));
+    }
+
+    #[test]
+    fn json_has_the_same_information() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        let mut cached = result("cached", TestStatus::Passed, Some(0));
+        cached.cached = true;
+        let report = TestReport::new(&[cached, result("slow", TestStatus::TimedOut, None)], dir).unwrap();
+        let path = dir.join("report.json");
+        report.write(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "tests": [
+                    {
+                        "name": "cached",
+                        "status": "passed",
+                        "time": 1.5,
+                        "exitCode": 0,
+                        "timedOut": false,
+                        "cached": true,
+                        "output": "",
+                        "cases": [],
+                    },
+                    {
+                        "name": "slow",
+                        "status": "timedOut",
+                        "time": 1.5,
+                        "exitCode": null,
+                        "timedOut": true,
+                        "cached": false,
+                        "output": "",
+                        "cases": [],
+                    },
+                ]
+            })
+        );
+    }
+}
@@ -0,0 +1,903 @@
+use crate::paths::{normalise, with_canonical};
+use crate::sandbox::SandboxAccess;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use Access::{Read, Stat, Write};
+use Follow::{IfFlag, UnlessFlag};
+
+/// The hidden subcommand that runs a command under the tracer. It is a
+/// separate process so that ptrace and `waitpid(-1)` don't interfere with
+/// the other commands that the build is running.
+pub const SUBCOMMAND: &str = "__trace";
+
+/// Used to give each traced command its own policy file.
+static NEXT_POLICY: AtomicUsize = AtomicUsize::new(0);
+
+/// What the tracer does with the paths that a command accesses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TraceMode {
+    /// Block access to paths that the command didn't declare.
+    Enforce,
+    /// Allow everything, but record every path in the sandboxed directories
+    /// that the command accesses.
+    Learn,
+}
+
+/// What a traced command may access, as passed to the tracer process.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TracePolicy {
+    mode: TraceMode,
+    sandboxed_dirs: Vec<String>,
+    read: Vec<String>,
+    write: Vec<String>,
+    network: bool,
+    /// Where to write the `TraceReport`.
+    report: PathBuf,
+}
+
+/// What the tracer found out about a command.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceReport {
+    /// Every path in the sandboxed directories that the command accessed,
+    /// with symlinks resolved. Only recorded in learn mode.
+    #[serde(default)]
+    pub accesses: Vec<FileAccess>,
+    /// Accesses that were blocked, in the order they happened.
+    #[serde(default)]
+    pub violations: Vec<Violation>,
+}
+
+/// A path that a command accessed, and how.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAccess {
+    pub path: PathBuf,
+    pub access: Access,
+}
+
+/// An access that the sandbox blocked.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Violation {
+    /// The path, or for `Access::Network` the address.
+    pub path: PathBuf,
+    pub access: Access,
+    /// The name of the syscall that was blocked.
+    pub syscall: String,
+}
+
+/// What the tracer has seen so far.
+#[derive(Default)]
+struct Observations {
+    accesses: BTreeSet<(PathBuf, Access)>,
+    violations: Vec<Violation>,
+}
+
+impl Observations {
+    fn add_violation(&mut self, violation: Violation) {
+        if !self.violations.contains(&violation) {
+            self.violations.push(violation);
+        }
+    }
+}
+
+/// A command set up to run under the tracer.
+pub struct TracedCommand {
+    pub command: Command,
+    /// Where the tracer will write its report.
+    pub report: PathBuf,
+}
+
+/// Create a `Command` that runs `command_line` under the tracer. The policy
+/// is written to a file in `policy_dir` because it can be too big for a
+/// single argument; the tracer deletes it once it has read it.
+pub fn traced_command(command_line: &[String], access: &SandboxAccess, policy_dir: &Path, mode: TraceMode) -> Result<TracedCommand> {
+    fs::create_dir_all(policy_dir).with_context(|| format!("Creating {:?}", policy_dir))?;
+    // The tracer runs in the command's working directory.
+    let policy_dir = fs::canonicalize(policy_dir)?;
+    let id = NEXT_POLICY.fetch_add(1, Ordering::Relaxed);
+    let policy_file = policy_dir.join(format!("trace-{}.json", id));
+    let report = policy_dir.join(format!("trace-{}-report.json", id));
+
+    let policy = TracePolicy {
+        mode,
+        sandboxed_dirs: access.sandboxed_dirs.to_vec(),
+        read: access.read.to_vec(),
+        write: access.write.to_vec(),
+        network: access.network,
+        report: report.clone(),
+    };
+    fs::write(&policy_file, serde_json::to_vec(&policy)?).with_context(|| format!("Writing {:?}", policy_file))?;
+
+    let mut c = Command::new(env::current_exe().context("Finding our own executable")?);
+    c.arg(SUBCOMMAND);
+    c.arg(&policy_file);
+    c.arg("--");
+    c.args(command_line);
+    Ok(TracedCommand { command: c, report })
+}
+
+/// Read and delete the report written by the tracer. There isn't one if the
+/// tracer was killed (e.g. because the command timed out), in which case
+/// the report is empty.
+pub fn read_report(path: &Path) -> Result<TraceReport> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(TraceReport::default()),
+        Err(e) => return Err(e).with_context(|| format!("Reading trace report {:?}", path)),
+    };
+    fs::remove_file(path)?;
+    serde_json::from_slice(&contents).with_context(|| format!("Parsing trace report {:?}", path))
+}
+
+/// Entry point for `build_exact __trace <policy file> -- <command...>`.
+/// Returns the exit code to exit with.
+pub fn main(args: &[OsString]) -> Result<i32> {
+    if args.len() < 3 || args[1] != "--" {
+        bail!("Usage: build_exact {} <policy file> -- <command>...", SUBCOMMAND);
+    }
+    let policy_file = Path::new(&args[0]);
+    let contents = fs::read(policy_file).with_context(|| format!("Reading {:?}", policy_file))?;
+    fs::remove_file(policy_file)?;
+    let policy: TracePolicy = serde_json::from_slice(&contents).with_context(|| format!("Parsing {:?}", policy_file))?;
+
+    let command_line = args[2..]
+        .iter()
+        .map(|arg| CString::new(arg.clone().into_vec()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut observations = Observations::default();
+    let status = trace(&Policy::new(&policy), &command_line, &mut observations)?;
+
+    let report = TraceReport {
+        accesses: observations.accesses.into_iter().map(|(path, access)| FileAccess { path, access }).collect(),
+        violations: observations.violations,
+    };
+    fs::write(&policy.report, serde_json::to_vec(&report)?).with_context(|| format!("Writing {:?}", policy.report))?;
+
+    if libc::WIFSIGNALED(status) {
+        // Die the same way so that our parent sees the same exit status.
+        unsafe {
+            libc::signal(libc::WTERMSIG(status), libc::SIG_DFL);
+            libc::kill(libc::getpid(), libc::WTERMSIG(status));
+        }
+        return Ok(128 + libc::WTERMSIG(status));
+    }
+    Ok(libc::WEXITSTATUS(status))
+}
+
+/// The kind of access a syscall makes to a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Access {
+    /// Looking at metadata (stat, access, readlink, opening a directory).
+    /// This is allowed for the directories that contain allowed files too.
+    Stat,
+    /// Reading or executing the contents.
+    Read,
+    /// Creating, modifying or deleting.
+    Write,
+    /// Connecting or sending to a network address other than loopback.
+    Network,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Access::Stat => "stat",
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Network => "network",
+        })
+    }
+}
+
+/// The paths a command may access. Declared paths are stored both as given
+/// and with symlinks resolved, since the paths the command uses are resolved
+/// before they are checked.
+struct Policy {
+    mode: TraceMode,
+    network: bool,
+    sandboxed_dirs: Vec<PathBuf>,
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+}
+
+impl Policy {
+    fn new(policy: &TracePolicy) -> Self {
+        Self {
+            mode: policy.mode,
+            network: policy.network,
+            sandboxed_dirs: with_canonical(&policy.sandboxed_dirs),
+            read: with_canonical(&policy.read),
+            write: with_canonical(&policy.write),
+        }
+    }
+
+    fn is_sandboxed(&self, path: &Path) -> bool {
+        self.sandboxed_dirs.iter().any(|dir| path.starts_with(dir))
+    }
+
+    fn allows(&self, path: &Path, access: Access) -> bool {
+        if access == Access::Network {
+            return self.network;
+        }
+        if self.mode == TraceMode::Learn || !self.is_sandboxed(path) {
+            return true;
+        }
+        let inside = |paths: &[PathBuf]| paths.iter().any(|allowed| path.starts_with(allowed));
+        match access {
+            Access::Write => inside(&self.write),
+            Access::Read => inside(&self.read) || inside(&self.write),
+            Access::Stat => {
+                inside(&self.read)
+                    || inside(&self.write)
+                    || self.read.iter().chain(self.write.iter()).any(|allowed| allowed.starts_with(path))
+            }
+            Access::Network => unreachable!(),
+        }
+    }
+}
+
+/// How to work out the access a path argument makes.
+#[derive(Clone, Copy)]
+enum AccessFrom {
+    Fixed(Access),
+    /// From `open()`-style flags in this argument.
+    OpenFlags(usize),
+    /// From the flags in the `struct open_how` pointed to by this argument.
+    OpenHow(usize),
+}
+
+/// Whether the syscall follows a symlink in the last component of the path.
+#[derive(Clone, Copy)]
+enum Follow {
+    Yes,
+    No,
+    /// Unless this flag is set in this argument.
+    UnlessFlag(usize, libc::c_int),
+    /// Only if this flag is set in this argument.
+    IfFlag(usize, libc::c_int),
+    /// Unless `O_NOFOLLOW` is in the open flags.
+    FromOpenFlags,
+}
+
+/// A path argument of a syscall.
+#[derive(Clone, Copy)]
+struct PathArg {
+    /// The argument with the directory file descriptor that relative paths
+    /// are relative to, or `None` for the working directory.
+    dirfd: Option<usize>,
+    path: usize,
+    access: AccessFrom,
+    follow: Follow,
+}
+
+const fn at(dirfd: usize, path: usize, access: Access, follow: Follow) -> PathArg {
+    PathArg {
+        dirfd: Some(dirfd),
+        path,
+        access: AccessFrom::Fixed(access),
+        follow,
+    }
+}
+
+const fn cwd(path: usize, access: Access, follow: Follow) -> PathArg {
+    PathArg {
+        dirfd: None,
+        path,
+        access: AccessFrom::Fixed(access),
+        follow,
+    }
+}
+
+struct Syscall {
+    nr: libc::c_long,
+    name: &'static str,
+    paths: &'static [PathArg],
+}
+
+/// Syscalls that are too new to be in libc for all architectures, but have
+/// the same number everywhere.
+const SYS_OPEN_TREE: libc::c_long = 428;
+const SYS_MOVE_MOUNT: libc::c_long = 429;
+const SYS_FSPICK: libc::c_long = 433;
+const SYS_MOUNT_SETATTR: libc::c_long = 442;
+const SYS_FCHMODAT2: libc::c_long = 452;
+const SYS_SETXATTRAT: libc::c_long = 463;
+const SYS_GETXATTRAT: libc::c_long = 464;
+const SYS_LISTXATTRAT: libc::c_long = 465;
+const SYS_REMOVEXATTRAT: libc::c_long = 466;
+
+/// Flags for the mount syscalls, which libc doesn't have for all
+/// architectures either.
+const UMOUNT_NOFOLLOW: libc::c_int = 0x8;
+const FAN_MARK_DONT_FOLLOW: libc::c_int = 0x4;
+const MOVE_MOUNT_F_SYMLINKS: libc::c_int = 0x1;
+const MOVE_MOUNT_T_SYMLINKS: libc::c_int = 0x10;
+const FSPICK_SYMLINK_NOFOLLOW: libc::c_int = 0x2;
+
+/// Every syscall that takes a path, on all architectures.
+const SYSCALLS: &[Syscall] = &[
+    Syscall { nr: libc::SYS_openat, name: "openat", paths: &[PathArg { dirfd: Some(0), path: 1, access: AccessFrom::OpenFlags(2), follow: Follow::FromOpenFlags }] },
+    Syscall { nr: libc::SYS_openat2, name: "openat2", paths: &[PathArg { dirfd: Some(0), path: 1, access: AccessFrom::OpenHow(2), follow: Follow::FromOpenFlags }] },
+    Syscall { nr: libc::SYS_newfstatat, name: "newfstatat", paths: &[at(0, 1, Stat, UnlessFlag(3, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_statx, name: "statx", paths: &[at(0, 1, Stat, UnlessFlag(2, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_faccessat, name: "faccessat", paths: &[at(0, 1, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_faccessat2, name: "faccessat2", paths: &[at(0, 1, Stat, UnlessFlag(3, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_readlinkat, name: "readlinkat", paths: &[at(0, 1, Stat, Follow::No)] },
+    Syscall { nr: libc::SYS_mkdirat, name: "mkdirat", paths: &[at(0, 1, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_mknodat, name: "mknodat", paths: &[at(0, 1, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_unlinkat, name: "unlinkat", paths: &[at(0, 1, Write, Follow::No)] },
+    // Not on newer architectures like riscv64, but aarch64 still has it and
+    // glibc's `rename()` uses it there.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    Syscall { nr: libc::SYS_renameat, name: "renameat", paths: &[at(0, 1, Write, Follow::No), at(2, 3, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_renameat2, name: "renameat2", paths: &[at(0, 1, Write, Follow::No), at(2, 3, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_linkat, name: "linkat", paths: &[at(0, 1, Read, IfFlag(4, libc::AT_SYMLINK_FOLLOW)), at(2, 3, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_symlinkat, name: "symlinkat", paths: &[at(1, 2, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_fchmodat, name: "fchmodat", paths: &[at(0, 1, Write, Follow::Yes)] },
+    Syscall { nr: SYS_FCHMODAT2, name: "fchmodat2", paths: &[at(0, 1, Write, UnlessFlag(3, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_fchownat, name: "fchownat", paths: &[at(0, 1, Write, UnlessFlag(4, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_utimensat, name: "utimensat", paths: &[at(0, 1, Write, UnlessFlag(3, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_execve, name: "execve", paths: &[cwd(0, Read, Follow::Yes)] },
+    Syscall { nr: libc::SYS_execveat, name: "execveat", paths: &[at(0, 1, Read, UnlessFlag(4, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_chdir, name: "chdir", paths: &[cwd(0, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_truncate, name: "truncate", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_getxattr, name: "getxattr", paths: &[cwd(0, Read, Follow::Yes)] },
+    Syscall { nr: libc::SYS_lgetxattr, name: "lgetxattr", paths: &[cwd(0, Read, Follow::No)] },
+    Syscall { nr: libc::SYS_setxattr, name: "setxattr", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_lsetxattr, name: "lsetxattr", paths: &[cwd(0, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_removexattr, name: "removexattr", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_lremovexattr, name: "lremovexattr", paths: &[cwd(0, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_listxattr, name: "listxattr", paths: &[cwd(0, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_llistxattr, name: "llistxattr", paths: &[cwd(0, Stat, Follow::No)] },
+    Syscall { nr: libc::SYS_statfs, name: "statfs", paths: &[cwd(0, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_inotify_add_watch, name: "inotify_add_watch", paths: &[cwd(1, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_name_to_handle_at, name: "name_to_handle_at", paths: &[at(0, 1, Stat, IfFlag(4, libc::AT_SYMLINK_FOLLOW))] },
+    Syscall { nr: SYS_SETXATTRAT, name: "setxattrat", paths: &[at(0, 1, Write, UnlessFlag(2, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: SYS_GETXATTRAT, name: "getxattrat", paths: &[at(0, 1, Read, UnlessFlag(2, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: SYS_LISTXATTRAT, name: "listxattrat", paths: &[at(0, 1, Stat, UnlessFlag(2, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: SYS_REMOVEXATTRAT, name: "removexattrat", paths: &[at(0, 1, Write, UnlessFlag(2, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_fanotify_mark, name: "fanotify_mark", paths: &[at(3, 4, Stat, UnlessFlag(1, FAN_MARK_DONT_FOLLOW))] },
+    Syscall { nr: libc::SYS_chroot, name: "chroot", paths: &[cwd(0, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_acct, name: "acct", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_quotactl, name: "quotactl", paths: &[cwd(1, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_swapon, name: "swapon", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_swapoff, name: "swapoff", paths: &[cwd(0, Write, Follow::Yes)] },
+    // Mounting over a path (in a user namespace that the command created)
+    // changes what is there. The source of a mount isn't always a path.
+    Syscall { nr: libc::SYS_mount, name: "mount", paths: &[cwd(1, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_umount2, name: "umount2", paths: &[cwd(0, Write, UnlessFlag(1, UMOUNT_NOFOLLOW))] },
+    Syscall { nr: libc::SYS_pivot_root, name: "pivot_root", paths: &[cwd(0, Write, Follow::Yes), cwd(1, Write, Follow::Yes)] },
+    Syscall { nr: SYS_OPEN_TREE, name: "open_tree", paths: &[at(0, 1, Read, UnlessFlag(2, libc::AT_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: SYS_MOVE_MOUNT, name: "move_mount", paths: &[at(0, 1, Write, IfFlag(4, MOVE_MOUNT_F_SYMLINKS)), at(2, 3, Write, IfFlag(4, MOVE_MOUNT_T_SYMLINKS))] },
+    Syscall { nr: SYS_FSPICK, name: "fspick", paths: &[at(0, 1, Write, UnlessFlag(2, FSPICK_SYMLINK_NOFOLLOW))] },
+    Syscall { nr: SYS_MOUNT_SETATTR, name: "mount_setattr", paths: &[at(0, 1, Write, UnlessFlag(2, libc::AT_SYMLINK_NOFOLLOW))] },
+];
+
+/// The old non-`at` syscalls, which newer architectures don't have.
+#[cfg(target_arch = "x86_64")]
+const LEGACY_SYSCALLS: &[Syscall] = &[
+    Syscall { nr: libc::SYS_open, name: "open", paths: &[PathArg { dirfd: None, path: 0, access: AccessFrom::OpenFlags(1), follow: Follow::FromOpenFlags }] },
+    Syscall { nr: libc::SYS_creat, name: "creat", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_stat, name: "stat", paths: &[cwd(0, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_lstat, name: "lstat", paths: &[cwd(0, Stat, Follow::No)] },
+    Syscall { nr: libc::SYS_access, name: "access", paths: &[cwd(0, Stat, Follow::Yes)] },
+    Syscall { nr: libc::SYS_readlink, name: "readlink", paths: &[cwd(0, Stat, Follow::No)] },
+    Syscall { nr: libc::SYS_mkdir, name: "mkdir", paths: &[cwd(0, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_rmdir, name: "rmdir", paths: &[cwd(0, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_unlink, name: "unlink", paths: &[cwd(0, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_rename, name: "rename", paths: &[cwd(0, Write, Follow::No), cwd(1, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_link, name: "link", paths: &[cwd(0, Read, Follow::No), cwd(1, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_symlink, name: "symlink", paths: &[cwd(1, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_chmod, name: "chmod", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_chown, name: "chown", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_lchown, name: "lchown", paths: &[cwd(0, Write, Follow::No)] },
+    Syscall { nr: libc::SYS_utime, name: "utime", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_utimes, name: "utimes", paths: &[cwd(0, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_futimesat, name: "futimesat", paths: &[at(0, 1, Write, Follow::Yes)] },
+    Syscall { nr: libc::SYS_mknod, name: "mknod", paths: &[cwd(0, Write, Follow::No)] },
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[Syscall] = &[];
+
+fn all_syscalls() -> impl Iterator<Item = &'static Syscall> {
+    SYSCALLS.iter().chain(LEGACY_SYSCALLS.iter())
+}
+
+/// A syscall that takes a network address.
+struct NetworkSyscall {
+    nr: libc::c_long,
+    name: &'static str,
+    /// The arguments with the `struct sockaddr` pointer and its length.
+    address: usize,
+    length: usize,
+}
+
+/// The syscalls that are checked for commands that can't use the network.
+/// They would fail anyway in the command's network namespace; this is so
+/// that the attempt can be reported.
+const NETWORK_SYSCALLS: &[NetworkSyscall] = &[
+    NetworkSyscall { nr: libc::SYS_connect, name: "connect", address: 1, length: 2 },
+    NetworkSyscall { nr: libc::SYS_sendto, name: "sendto", address: 4, length: 5 },
+];
+
+/// Architecture specific register access.
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use std::io;
+
+    pub const AUDIT_ARCH: u32 = 0xC000_003E;
+
+    /// Syscalls with this bit set use the x32 ABI, which has different
+    /// numbers, so they are blocked.
+    pub const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    pub type Registers = libc::user_regs_struct;
+
+    pub fn syscall_number(regs: &Registers) -> libc::c_long {
+        regs.orig_rax as libc::c_long
+    }
+
+    pub fn syscall_args(regs: &Registers) -> [u64; 6] {
+        [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9]
+    }
+
+    /// Skip the syscall that the tracee is stopped at and make it return
+    /// `-errno`.
+    pub fn skip_syscall(pid: libc::pid_t, regs: &Registers, errno: i32) -> io::Result<()> {
+        let mut regs = *regs;
+        regs.orig_rax = u64::MAX;
+        regs.rax = (-(errno as i64)) as u64;
+        super::set_registers(pid, &regs)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use std::io;
+
+    pub const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// aarch64 has no alternative syscall ABI to block.
+    pub const X32_SYSCALL_BIT: u32 = 0;
+
+    /// Register set for changing the syscall number.
+    const NT_ARM_SYSTEM_CALL: libc::c_int = 0x404;
+
+    pub type Registers = libc::user_regs_struct;
+
+    pub fn syscall_number(regs: &Registers) -> libc::c_long {
+        regs.regs[8] as libc::c_long
+    }
+
+    pub fn syscall_args(regs: &Registers) -> [u64; 6] {
+        [regs.regs[0], regs.regs[1], regs.regs[2], regs.regs[3], regs.regs[4], regs.regs[5]]
+    }
+
+    /// Skip the syscall that the tracee is stopped at and make it return
+    /// `-errno`.
+    pub fn skip_syscall(pid: libc::pid_t, regs: &Registers, errno: i32) -> io::Result<()> {
+        let mut syscall: libc::c_int = -1;
+        let mut iov = libc::iovec {
+            iov_base: (&mut syscall as *mut libc::c_int).cast(),
+            iov_len: std::mem::size_of::<libc::c_int>(),
+        };
+        if unsafe { libc::ptrace(libc::PTRACE_SETREGSET, pid, NT_ARM_SYSTEM_CALL, &mut iov) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut regs = *regs;
+        regs.regs[0] = (-(errno as i64)) as u64;
+        super::set_registers(pid, &regs)
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("The tracer only supports x86_64 and aarch64");
+
+fn get_registers(pid: libc::pid_t) -> io::Result<arch::Registers> {
+    let mut regs: arch::Registers = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: (&mut regs as *mut arch::Registers).cast(),
+        iov_len: std::mem::size_of::<arch::Registers>(),
+    };
+    if unsafe { libc::ptrace(libc::PTRACE_GETREGSET, pid, libc::NT_PRSTATUS, &mut iov) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(regs)
+}
+
+fn set_registers(pid: libc::pid_t, regs: &arch::Registers) -> io::Result<()> {
+    let mut regs = *regs;
+    let mut iov = libc::iovec {
+        iov_base: (&mut regs as *mut arch::Registers).cast(),
+        iov_len: std::mem::size_of::<arch::Registers>(),
+    };
+    if unsafe { libc::ptrace(libc::PTRACE_SETREGSET, pid, libc::NT_PRSTATUS, &mut iov) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Build the seccomp filter, which makes every path syscall (and network
+/// syscall, if the command can't use the network) stop for the tracer and
+/// allows everything else.
+fn seccomp_filter(policy: &Policy) -> Vec<libc::sock_filter> {
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+    }
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code: code as u16, jt, jf, k }
+    }
+
+    // Offsets in `struct seccomp_data`.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    let mut filter = vec![
+        // Kill processes using a different syscall ABI (e.g. 32-bit x86),
+        // since their syscall numbers are different.
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch::AUDIT_ARCH, 1, 0),
+        statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+    ];
+    if arch::X32_SYSCALL_BIT != 0 {
+        filter.push(jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, arch::X32_SYSCALL_BIT, 0, 1));
+        filter.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS));
+    }
+    let network_syscalls = if policy.network { &[] } else { NETWORK_SYSCALLS };
+    let traced = all_syscalls().map(|syscall| syscall.nr).chain(network_syscalls.iter().map(|syscall| syscall.nr));
+    for nr in traced {
+        filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 1));
+        filter.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_TRACE));
+    }
+    filter.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter
+}
+
+/// Run `command_line` and trace it and all its descendants until it exits.
+/// What they access is added to `observations`. Returns its wait status.
+fn trace(policy: &Policy, command_line: &[CString], observations: &mut Observations) -> Result<libc::c_int> {
+    let filter = seccomp_filter(policy);
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    let mut argv: Vec<*const libc::c_char> = command_line.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(ptr::null());
+
+    let root = unsafe { libc::fork() };
+    if root < 0 {
+        return Err(io::Error::last_os_error()).context("fork");
+    }
+    if root == 0 {
+        unsafe { exec_child(&program, &argv) };
+    }
+
+    // Wait for the child to stop itself so we can set the options before it
+    // installs the filter.
+    let mut status = 0;
+    if unsafe { libc::waitpid(root, &mut status, libc::__WALL) } < 0 {
+        return Err(io::Error::last_os_error()).context("waitpid");
+    }
+    if !libc::WIFSTOPPED(status) {
+        bail!("Traced process didn't start");
+    }
+    let options = libc::PTRACE_O_TRACESECCOMP
+        | libc::PTRACE_O_TRACEFORK
+        | libc::PTRACE_O_TRACEVFORK
+        | libc::PTRACE_O_TRACECLONE
+        | libc::PTRACE_O_TRACEEXEC
+        | libc::PTRACE_O_EXITKILL;
+    if unsafe { libc::ptrace(libc::PTRACE_SETOPTIONS, root, 0, options) } != 0 {
+        return Err(io::Error::last_os_error()).context("PTRACE_SETOPTIONS");
+    }
+    resume(root, 0);
+
+    loop {
+        let pid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+        if pid < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error).context("waitpid");
+        }
+
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            // Any remaining descendants are killed when we exit.
+            if pid == root {
+                return Ok(status);
+            }
+            continue;
+        }
+        if !libc::WIFSTOPPED(status) {
+            continue;
+        }
+
+        let signal = match status >> 16 {
+            libc::PTRACE_EVENT_SECCOMP => {
+                check_syscall(pid, policy, observations);
+                0
+            }
+            // Other ptrace events (fork, exec, etc.). New processes are
+            // traced automatically.
+            event if event != 0 => 0,
+            _ => match libc::WSTOPSIG(status) {
+                // New processes start with SIGSTOP. Stop signals are ignored
+                // anyway because a stopped tracee would hang the build.
+                libc::SIGSTOP | libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU => 0,
+                signal => signal,
+            },
+        };
+        resume(pid, signal);
+    }
+}
+
+/// Continue a stopped tracee, delivering `signal` if it isn't 0. It may
+/// have been killed in the meantime, so errors are ignored.
+fn resume(pid: libc::pid_t, signal: libc::c_int) {
+    unsafe {
+        libc::ptrace(libc::PTRACE_CONT, pid, 0, signal as libc::c_long);
+    }
+}
+
+/// In the forked child: wait to be traced, install the filter and exec the
+/// command. Every path syscall from here on, including the `execve()`, is
+/// checked.
+unsafe fn exec_child(program: &libc::sock_fprog, argv: &[*const libc::c_char]) -> ! {
+    libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+    libc::kill(libc::getpid(), libc::SIGSTOP);
+
+    // Needed to install a filter without CAP_SYS_ADMIN.
+    if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+        || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, program as *const libc::sock_fprog) != 0
+    {
+        let message = b"build_exact: failed to install the seccomp filter\n";
+        libc::write(2, message.as_ptr().cast(), message.len());
+        libc::_exit(127);
+    }
+
+    libc::execvp(argv[0], argv.as_ptr());
+    let message = b"build_exact: failed to execute command\n";
+    libc::write(2, message.as_ptr().cast(), message.len());
+    libc::_exit(127);
+}
+
+/// Check the paths used by the syscall that `pid` is stopped at, and make it
+/// fail with EACCES (or ENOENT when reading a file that doesn't exist) if
+/// any of them aren't allowed. Blocked accesses are recorded as violations,
+/// and in learn mode all the paths are recorded instead.
+fn check_syscall(pid: libc::pid_t, policy: &Policy, observations: &mut Observations) {
+    // The tracee may have been killed, in which case there's nothing to do.
+    let regs = match get_registers(pid) {
+        Ok(regs) => regs,
+        Err(_) => return,
+    };
+    let nr = arch::syscall_number(&regs);
+    let args = arch::syscall_args(&regs);
+    if let Some(syscall) = NETWORK_SYSCALLS.iter().find(|syscall| syscall.nr == nr) {
+        check_network_syscall(pid, syscall, &args, policy, observations);
+        return;
+    }
+    let syscall = match all_syscalls().find(|syscall| syscall.nr == nr) {
+        Some(syscall) => syscall,
+        None => return,
+    };
+
+    for path_arg in syscall.paths {
+        let (path, access) = match resolve_path_arg(pid, path_arg, &args) {
+            Some(resolved) => resolved,
+            None => continue,
+        };
+        if policy.mode == TraceMode::Learn && policy.is_sandboxed(&path) {
+            observations.accesses.insert((path.clone(), access));
+        }
+        if !policy.allows(&path, access) {
+            // Tools often probe for files that don't exist (e.g. searching
+            // include paths), so reading those just makes them look missing.
+            let errno = if access != Access::Write && fs::symlink_metadata(&path).is_err() {
+                libc::ENOENT
+            } else {
+                observations.add_violation(Violation {
+                    path,
+                    access,
+                    syscall: syscall.name.to_string(),
+                });
+                libc::EACCES
+            };
+            if let Err(e) = arch::skip_syscall(pid, &regs, errno) {
+                eprintln!("build_exact: failed to block syscall: {}", e);
+            }
+            return;
+        }
+    }
+}
+
+/// Record an attempt to use an address other than loopback. The syscall is
+/// left to fail in the network namespace.
+fn check_network_syscall(
+    pid: libc::pid_t,
+    syscall: &NetworkSyscall,
+    args: &[u64; 6],
+    policy: &Policy,
+    observations: &mut Observations,
+) {
+    let address = match read_socket_address(pid, args[syscall.address], args[syscall.length]) {
+        Some(address) => address,
+        None => return,
+    };
+    let ip = address.ip();
+    let local = ip.is_loopback()
+        || ip.is_unspecified()
+        || matches!(ip, IpAddr::V6(ip) if ip.to_ipv4_mapped().map(|ip| ip.is_loopback()).unwrap_or(false));
+    if !local && !policy.allows(Path::new(&address.to_string()), Access::Network) {
+        observations.add_violation(Violation {
+            path: PathBuf::from(address.to_string()),
+            access: Access::Network,
+            syscall: syscall.name.to_string(),
+        });
+    }
+}
+
+/// Read an IPv4 or IPv6 `struct sockaddr` from the tracee's memory.
+fn read_socket_address(pid: libc::pid_t, address: u64, length: u64) -> Option<SocketAddr> {
+    let mut buffer = [0u8; std::mem::size_of::<libc::sockaddr_in6>()];
+    let length = (length as usize).min(buffer.len());
+    if address == 0 || length < 2 {
+        return None;
+    }
+    let read = read_memory(pid, address as usize, &mut buffer[..length])?;
+
+    let family = libc::c_int::from(u16::from_ne_bytes([buffer[0], buffer[1]]));
+    let port = u16::from_be_bytes([buffer[2], buffer[3]]);
+    match family {
+        libc::AF_INET if read >= 8 => Some(SocketAddr::from((Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]), port))),
+        libc::AF_INET6 if read >= 24 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buffer[8..24]);
+            Some(SocketAddr::from((Ipv6Addr::from(octets), port)))
+        }
+        _ => None,
+    }
+}
+
+/// Work out the absolute path (with symlinks resolved the same way the
+/// kernel will) and the kind of access for a path argument. Returns `None`
+/// if there's no path to check, e.g. because it is NULL or empty (so the
+/// syscall operates on a file descriptor), or can't be read (in which case
+/// the syscall will fail anyway).
+fn resolve_path_arg(pid: libc::pid_t, path_arg: &PathArg, args: &[u64; 6]) -> Option<(PathBuf, Access)> {
+    let path = read_string(pid, args[path_arg.path])?;
+    if path.is_empty() {
+        return None;
+    }
+
+    let open_flags = match path_arg.access {
+        AccessFrom::Fixed(_) => 0,
+        AccessFrom::OpenFlags(arg) => args[arg] as libc::c_int,
+        // The flags are the first field of `struct open_how`.
+        AccessFrom::OpenHow(arg) => read_u64(pid, args[arg])? as libc::c_int,
+    };
+    let access = match path_arg.access {
+        AccessFrom::Fixed(access) => access,
+        AccessFrom::OpenFlags(_) | AccessFrom::OpenHow(_) => open_access(open_flags),
+    };
+    let follow = match path_arg.follow {
+        Follow::Yes => true,
+        Follow::No => false,
+        Follow::UnlessFlag(arg, flag) => args[arg] as libc::c_int & flag == 0,
+        Follow::IfFlag(arg, flag) => args[arg] as libc::c_int & flag != 0,
+        Follow::FromOpenFlags => open_flags & libc::O_NOFOLLOW == 0,
+    };
+
+    let path = PathBuf::from(OsString::from_vec(path));
+    let path = if path.is_absolute() {
+        path
+    } else {
+        let dirfd = path_arg.dirfd.map(|arg| args[arg] as libc::c_int).unwrap_or(libc::AT_FDCWD);
+        let base = if dirfd == libc::AT_FDCWD {
+            fs::read_link(format!("/proc/{}/cwd", pid)).ok()?
+        } else {
+            fs::read_link(format!("/proc/{}/fd/{}", pid, dirfd)).ok()?
+        };
+        base.join(path)
+    };
+
+    Some((resolve_symlinks(&path, follow), access))
+}
+
+/// The kind of access `open()` with these flags makes.
+fn open_access(flags: libc::c_int) -> Access {
+    if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & (libc::O_CREAT | libc::O_TRUNC) != 0 {
+        Access::Write
+    } else if flags & (libc::O_DIRECTORY | libc::O_PATH) != 0 {
+        Access::Stat
+    } else {
+        Access::Read
+    }
+}
+
+/// Resolve symlinks in an absolute path, including the last component only
+/// if `follow` is true. Parts that don't exist are normalised lexically.
+fn resolve_symlinks(path: &Path, follow: bool) -> PathBuf {
+    if follow {
+        if let Ok(canonical) = fs::canonicalize(path) {
+            return canonical;
+        }
+    }
+    let path = normalise(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match fs::canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => path,
+        },
+        _ => path,
+    }
+}
+
+/// Read a nul-terminated string from the tracee's memory.
+fn read_string(pid: libc::pid_t, address: u64) -> Option<Vec<u8>> {
+    if address == 0 {
+        return None;
+    }
+
+    let mut string = Vec::new();
+    let mut address = address as usize;
+    while string.len() < libc::PATH_MAX as usize {
+        // Don't read across a page boundary in one go, since the next page
+        // might not be mapped.
+        let chunk_len = 4096 - address % 4096;
+        let mut buffer = vec![0u8; chunk_len];
+        let read = read_memory(pid, address, &mut buffer)?;
+        if let Some(end) = buffer[..read].iter().position(|&b| b == 0) {
+            string.extend_from_slice(&buffer[..end]);
+            return Some(string);
+        }
+        string.extend_from_slice(&buffer[..read]);
+        address += read;
+    }
+    None
+}
+
+fn read_u64(pid: libc::pid_t, address: u64) -> Option<u64> {
+    let mut buffer = [0u8; 8];
+    if read_memory(pid, address as usize, &mut buffer)? != buffer.len() {
+        return None;
+    }
+    Some(u64::from_ne_bytes(buffer))
+}
+
+fn read_memory(pid: libc::pid_t, address: usize, buffer: &mut [u8]) -> Option<usize> {
+    let local = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+    if read <= 0 {
+        return None;
+    }
+    Some(read as usize)
+}